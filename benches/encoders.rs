@@ -0,0 +1,25 @@
+// The matcher against the original sliding window encoder, on the bundled vocab and the sample text the CLI bench uses
+// cargo bench --features bench --bench encoders
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rs_tokeniser::Tokeniser;
+
+const SAMPLE: &str = include_str!("../fixtures/sample.txt");
+
+fn encoders(c: &mut Criterion) {
+    let tokeniser = Tokeniser::bundled(rs_tokeniser::tokeniser::DEFAULT_BUNDLED).unwrap();
+
+    let mut group = c.benchmark_group("encoders");
+    for (index, text) in SAMPLE.lines().enumerate() {
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_with_input(BenchmarkId::new("sliding window", index), text, |b, text| {
+            b.iter(|| tokeniser._get_tokens_from_text_sliding(text))
+        });
+        group.bench_with_input(BenchmarkId::new("matcher", index), text, |b, text| {
+            b.iter(|| tokeniser.get_tokens_from_text(text).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encoders);
+criterion_main!(benches);
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// GPT-2 style byte to unicode mapping. Every byte gets a printable character so tokens stay readable strings:
+// the printable ASCII and Latin-1 bytes map to themselves, everything else (control characters, space, ...) is
+// shifted up past 255
+fn byte_chars() -> &'static [char; 256] {
+    static BYTE_CHARS: OnceLock<[char; 256]> = OnceLock::new();
+    BYTE_CHARS.get_or_init(|| {
+        let mut chars = ['\0'; 256];
+        let mut shifted = 0;
+        for byte in 0..=255u8 {
+            let printable = matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+            chars[byte as usize] = if printable {
+                byte as char
+            } else {
+                shifted += 1;
+                char::from_u32(255 + shifted).unwrap() // always a valid scalar value this low
+            };
+        }
+        chars
+    })
+}
+
+fn char_bytes() -> &'static HashMap<char, u8> {
+    static CHAR_BYTES: OnceLock<HashMap<char, u8>> = OnceLock::new();
+    CHAR_BYTES.get_or_init(|| {
+        byte_chars().iter().enumerate().map(|(byte, &c)| (c, byte as u8)).collect()
+    })
+}
+
+// Maps every byte of the input to its character, so any input (emoji, CJK, binary) becomes a string over 256 symbols
+pub fn encode(bytes: &[u8]) -> String {
+    let chars = byte_chars();
+    bytes.iter().map(|&byte| chars[byte as usize]).collect()
+}
+
+// The character a single byte maps to
+pub fn symbol(byte: u8) -> char {
+    byte_chars()[byte as usize]
+}
+
+// Reverses encode(). Anything outside the byte alphabet is passed through as its own UTF-8 bytes
+pub fn decode(text: &str) -> Vec<u8> {
+    let bytes = char_bytes();
+    let mut output = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match bytes.get(&c) {
+            Some(&byte) => output.push(byte),
+            None => output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    output
+}
+
+// The 256 base tokens - add these to a byte level vocab so every input is encodable even if the corpus never had the byte
+pub fn alphabet() -> Vec<String> {
+    byte_chars().iter().map(|c| c.to_string()).collect()
+}
+
+// A training corpus of one token per byte, ready for Trainer::new
+pub fn corpus(bytes: &[u8]) -> Vec<String> {
+    let chars = byte_chars();
+    bytes.iter().map(|&byte| chars[byte as usize].to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokeniser::Tokeniser;
+    use crate::trainer::Trainer;
+
+    #[test]
+    fn every_byte_round_trips() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let encoded = encode(&bytes);
+
+        assert_eq!(encoded.chars().count(), 256);
+        assert_eq!(decode(&encoded), bytes);
+        assert_eq!(encode(b"hello world"), "hello\u{120}world"); // space becomes Ġ like GPT-2
+    }
+
+    #[test]
+    fn byte_level_tokeniser_is_lossless() {
+        let training = "the cat sat on the mat, the rat sat on the hat.\nThe End!";
+        let mut trainer = Trainer::new(corpus(training.as_bytes()), HashMap::new()).with_alphabet(&alphabet());
+        trainer.train(300).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+        let tokeniser = Tokeniser::from_vocab(&vocab, merges).with_byte_level(true);
+
+        for input in ["the cat sat", "Ünïcödé 東京 🦀\r\n\tfn main() { }", ""] {
+            let tokens = tokeniser.encode(input).unwrap();
+            assert_eq!(tokeniser.reconstruct(&tokens).unwrap(), input);
+        }
+
+        let binary = [0u8, 159, 146, 150, 255, b'\n'];
+        assert_eq!(tokeniser.reconstruct_bytes(&tokeniser.encode_bytes(&binary).unwrap()).unwrap(), binary);
+    }
+}
@@ -0,0 +1,75 @@
+use std::ops::Range;
+
+use colored::{ColoredString, Colorize, CustomColor};
+
+// One encoded text on its own, so the Tokeniser that made it can stay shared and untouched.
+// All four lists line up: token i has id ids[i], is tokens[i] and came from offsets[i] (see TokenOffsets)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Encoding {
+    pub ids: Vec<usize>,
+    pub tokens: Vec<String>, // as they are in the vocab, so byte level tokens are in byte_level's symbols
+    pub offsets: Vec<Range<usize>>, // bytes of the input each token came from, before normalisation
+    pub char_offsets: Vec<Range<usize>>, // the same in chars
+}
+
+impl Encoding {
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    // The colour each token is shown in
+    pub fn colours(&self) -> Vec<(u8, u8, u8)> {
+        self.tokens.iter().map(|token| token_colour(token)).collect()
+    }
+
+    // Every token in its colour, for printing to a terminal
+    pub fn coloured(&self) -> Vec<ColoredString> {
+        self.tokens.iter()
+            .map(|token| {
+                let (r, g, b) = token_colour(token);
+                token.as_str().custom_color(CustomColor { r, g, b })
+            })
+            .collect()
+    }
+
+    pub fn pretty_print(&self) {
+        for token in self.coloured() {
+            print!("{}", token);
+        }
+        println!();
+    }
+}
+
+// The same token is always the same colour, and light enough to read on a dark terminal
+pub fn token_colour(token: &str) -> (u8, u8, u8) {
+    let mut hash: u32 = 2166136261; // FNV-1a
+    for byte in token.bytes() {
+        hash = (hash ^ u32::from(byte)).wrapping_mul(16777619);
+    }
+    let channel = |shift: u32| 128 + ((hash >> shift) as u8 >> 1);
+    (channel(16), channel(8), channel(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_keep_their_colour() {
+        let encoding = Encoding {
+            ids: vec![0, 1, 0],
+            tokens: vec!["the".to_string(), " cat".to_string(), "the".to_string()],
+            offsets: vec![0..3, 3..7, 7..10],
+            char_offsets: vec![0..3, 3..7, 7..10],
+        };
+        let colours = encoding.colours();
+        assert_eq!(colours[0], colours[2]);
+        assert_ne!(colours[0], colours[1]);
+        assert!(colours.iter().all(|&(r, g, b)| r >= 128 && g >= 128 && b >= 128));
+        assert_eq!(encoding.coloured().len(), encoding.len());
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::tokeniser::UnencodableInput;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    MissingVocab(PathBuf), // no vocabulary file at this path (or no bundled vocabulary by that name)
+    MalformedVocab { source: String, error: serde_json::Error }, // the file (or reader) wasn't the JSON we expected
+    MalformedRanks { source: String, line: usize, reason: String }, // a line of a tiktoken rank file that couldn't be read (line counts from 1)
+    Unencodable(UnencodableInput), // the input had text the vocab can't encode, under UnknownPolicy::Error
+    Training(String), // training couldn't produce a vocabulary
+    MalformedTokens(String), // a token id file (see token_file) that couldn't be read
+    VocabMismatch { expected: u64, found: u64 }, // token ids written with one vocab being read with another (see Tokeniser::vocab_hash)
+    Unsupported(String), // a model file (eg Hugging Face) that uses something this tokeniser can't do
+    InvalidPattern(regex::Error), // a pre-tokeniser split pattern that isn't a valid regex
+    InvalidTruncation(String), // truncation settings that can't cut anything up (see model_input::Truncation)
+    UnknownId { id: usize, vocab_size: usize }, // decoding an id that isn't in the vocab
+    Io(io::Error),
+}
+
+impl Error {
+    pub(crate) fn malformed(source: impl fmt::Display, error: serde_json::Error) -> Self {
+        Error::MalformedVocab { source: source.to_string(), error }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingVocab(path) => write!(f, "vocab file {} does not exist", path.display()),
+            Error::MalformedVocab { source, error } => write!(f, "malformed vocab in {}: {}", source, error),
+            Error::MalformedRanks { source, line, reason } => write!(f, "malformed ranks in {} line {}: {}", source, line, reason),
+            Error::Unencodable(input) => write!(f, "{}", input),
+            Error::Training(reason) => write!(f, "training failed: {}", reason),
+            Error::MalformedTokens(reason) => write!(f, "malformed token file: {}", reason),
+            Error::VocabMismatch { expected, found } => write!(f, "the tokens were written with vocab {:016x}, not this one ({:016x})", expected, found),
+            Error::Unsupported(reason) => write!(f, "unsupported model: {}", reason),
+            Error::InvalidPattern(error) => write!(f, "invalid split pattern: {}", error),
+            Error::InvalidTruncation(reason) => write!(f, "invalid truncation: {}", reason),
+            Error::UnknownId { id, vocab_size } => write!(f, "token id {} is out of range for a vocab of {} tokens", id, vocab_size),
+            Error::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MalformedVocab { error, .. } => Some(error),
+            Error::Unencodable(input) => Some(input),
+            Error::InvalidPattern(error) => Some(error),
+            Error::Io(error) => Some(error),
+            Error::MissingVocab(_) | Error::MalformedRanks { .. } | Error::Training(_) | Error::MalformedTokens(_) | Error::VocabMismatch { .. } | Error::Unsupported(_)
+            | Error::InvalidTruncation(_) | Error::UnknownId { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<UnencodableInput> for Error {
+    fn from(input: UnencodableInput) -> Self {
+        Error::Unencodable(input)
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::{Error, Result};
+use crate::normaliser::{Normaliser, UnicodeForm};
+use crate::pre_tokeniser::{PreTokeniser, SplitPattern, GPT2_PATTERN};
+use crate::tokeniser::{Merge, Tokeniser, UnknownPolicy, UNKNOWN_TOKEN};
+
+// The parts of a Hugging Face tokenizer.json that map onto a Tokeniser. Only BPE models are supported, and a
+// normalizer or pre_tokenizer of a type that isn't listed here fails to parse rather than being silently ignored
+#[derive(Serialize, Deserialize)]
+struct TokenizerJson {
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    truncation: Option<Value>, // not used, written as null
+    #[serde(default)]
+    padding: Option<Value>, // not used, written as null
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    #[serde(default)]
+    normalizer: Option<NormalizerJson>,
+    #[serde(default)]
+    pre_tokenizer: Option<PreTokenizerJson>,
+    #[serde(default)]
+    post_processor: Option<Value>, // not used, written as null
+    #[serde(default)]
+    decoder: Option<Value>, // always follows from the rest of the config here, so it's only written
+    model: ModelJson,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AddedToken {
+    id: usize,
+    content: String,
+    #[serde(default)]
+    single_word: bool,
+    #[serde(default)]
+    lstrip: bool,
+    #[serde(default)]
+    rstrip: bool,
+    #[serde(default)]
+    normalized: bool,
+    #[serde(default)]
+    special: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum NormalizerJson {
+    Sequence { normalizers: Vec<NormalizerJson> },
+    #[serde(rename = "NFC")]
+    Nfc,
+    #[serde(rename = "NFKC")]
+    Nfkc,
+    #[serde(rename = "NFD")]
+    Nfd,
+    StripAccents,
+    Lowercase,
+    Replace { pattern: PatternJson, content: String },
+    #[serde(other)]
+    Other, // Prepend, Strip, ... are valid files this can't run, rather than malformed ones
+}
+
+#[derive(Serialize, Deserialize)]
+enum PatternJson {
+    String(String),
+    Regex(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum PreTokenizerJson {
+    Sequence { pretokenizers: Vec<PreTokenizerJson> },
+    Split { pattern: PatternJson, behavior: String, invert: bool },
+    Digits { individual_digits: bool },
+    ByteLevel { add_prefix_space: bool, trim_offsets: bool, use_regex: bool },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ModelJson {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    dropout: Option<f32>,
+    #[serde(default)]
+    unk_token: Option<String>,
+    #[serde(default)]
+    continuing_subword_prefix: Option<String>,
+    #[serde(default)]
+    end_of_word_suffix: Option<String>,
+    #[serde(default)]
+    fuse_unk: bool,
+    #[serde(default)]
+    byte_fallback: bool,
+    #[serde(default)]
+    ignore_merges: bool,
+    vocab: BTreeMap<String, usize>,
+    merges: Vec<MergeJson>,
+}
+
+// Older files write merges as "left right", newer ones as ["left", "right"] which also allows tokens with spaces in them
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum MergeJson {
+    Pair(String, String),
+    Joined(String),
+}
+
+// Whitespace runs are collapsed with a regex Replace
+const WHITESPACE_RUN: &str = r"\s+";
+
+impl NormalizerJson {
+    fn from_normaliser(normaliser: Normaliser) -> Option<Self> {
+        let mut normalizers = Vec::new();
+        match normaliser.form {
+            Some(UnicodeForm::Nfc) => normalizers.push(NormalizerJson::Nfc),
+            Some(UnicodeForm::Nfkc) => normalizers.push(NormalizerJson::Nfkc),
+            None => {},
+        }
+        if normaliser.strip_accents { // StripAccents only drops marks that are already separate, so decompose first and compose again after
+            normalizers.extend([NormalizerJson::Nfd, NormalizerJson::StripAccents, NormalizerJson::Nfc]);
+        }
+        if normaliser.lowercase {
+            normalizers.push(NormalizerJson::Lowercase);
+        }
+        if normaliser.strip_newlines {
+            normalizers.push(NormalizerJson::Replace { pattern: PatternJson::String("\n".to_string()), content: String::new() });
+        }
+        if normaliser.collapse_whitespace {
+            normalizers.push(NormalizerJson::Replace { pattern: PatternJson::Regex(WHITESPACE_RUN.to_string()), content: " ".to_string() });
+        }
+
+        match normalizers.len() {
+            0 => None,
+            1 => normalizers.pop(),
+            _ => Some(NormalizerJson::Sequence { normalizers }),
+        }
+    }
+
+    // Sequences flattened into the steps they run, in order
+    fn steps<'a>(&'a self, steps: &mut Vec<&'a NormalizerJson>) {
+        match self {
+            NormalizerJson::Sequence { normalizers } => {
+                for normalizer in normalizers {
+                    normalizer.steps(steps);
+                }
+            },
+            step => steps.push(step),
+        }
+    }
+
+    // The Normaliser always runs its steps in the same order, which only differs from the file's order in
+    // ways that don't matter for these steps (eg lowercasing before or after composing)
+    fn to_normaliser(&self) -> Result<Normaliser> {
+        let mut steps = Vec::new();
+        self.steps(&mut steps);
+
+        let mut normaliser = Normaliser::none();
+        let mut steps = steps.into_iter().peekable();
+        while let Some(step) = steps.next() {
+            match step {
+                NormalizerJson::Sequence { .. } => {}, // flattened already
+                NormalizerJson::Nfc => normaliser.form = Some(UnicodeForm::Nfc),
+                NormalizerJson::Nfkc => normaliser.form = Some(UnicodeForm::Nfkc),
+                NormalizerJson::Nfd => {
+                    // only as the decompose, StripAccents, compose again that from_normaliser() writes
+                    if steps.next_if(|step| matches!(step, NormalizerJson::StripAccents)).is_none() {
+                        return Err(Error::Unsupported("NFD other than before StripAccents".to_string()));
+                    }
+                    steps.next_if(|step| matches!(step, NormalizerJson::Nfc));
+                    normaliser.strip_accents = true;
+                },
+                NormalizerJson::StripAccents => return Err(Error::Unsupported("StripAccents without NFD before it".to_string())),
+                NormalizerJson::Lowercase => normaliser.lowercase = true,
+                NormalizerJson::Replace { pattern: PatternJson::String(pattern), content } if pattern == "\n" && content.is_empty() => {
+                    normaliser.strip_newlines = true;
+                },
+                NormalizerJson::Replace { pattern: PatternJson::Regex(pattern), content } if pattern == WHITESPACE_RUN && content == " " => {
+                    normaliser.collapse_whitespace = true;
+                },
+                NormalizerJson::Replace { .. } => {
+                    return Err(Error::Unsupported("Replace normalizers other than removing \\n or collapsing whitespace".to_string()));
+                },
+                NormalizerJson::Other => {
+                    return Err(Error::Unsupported("normalizers other than NFC, NFKC, StripAccents, Lowercase and Replace".to_string()));
+                },
+            }
+        }
+        Ok(normaliser)
+    }
+}
+
+impl PreTokenizerJson {
+    // The steps Hugging Face runs in order: split, isolate digits, map to bytes. A ByteLevel with use_regex does
+    // the GPT-2 split itself, so that's all it takes for the most common case
+    fn from_config(pre_tokeniser: &PreTokeniser, byte_level: bool) -> Option<Self> {
+        let gpt2 = *pre_tokeniser.pattern() == SplitPattern::Gpt2;
+        let mut pretokenizers = Vec::new();
+        if let Some(pattern) = pre_tokeniser.pattern().source().filter(|_| !(gpt2 && byte_level)) {
+            pretokenizers.push(PreTokenizerJson::Split { pattern: PatternJson::Regex(pattern.to_string()), behavior: "Isolated".to_string(), invert: false });
+        }
+        if pre_tokeniser.splits_digits() {
+            pretokenizers.push(PreTokenizerJson::Digits { individual_digits: true });
+        }
+        if byte_level {
+            pretokenizers.push(PreTokenizerJson::ByteLevel { add_prefix_space: false, trim_offsets: false, use_regex: gpt2 });
+        }
+
+        match pretokenizers.len() {
+            0 => None,
+            1 => pretokenizers.pop(),
+            _ => Some(PreTokenizerJson::Sequence { pretokenizers }),
+        }
+    }
+
+    // Collects the split pattern, digit splitting and byte level setting. Only one step can set the pattern
+    fn apply_to(&self, pattern: &mut Option<SplitPattern>, split_digits: &mut bool, byte_level: &mut bool) -> Result<()> {
+        let mut set_pattern = |split: SplitPattern| match pattern {
+            Some(_) => Err(Error::Unsupported("more than one split pattern".to_string())),
+            None => {
+                *pattern = Some(split);
+                Ok(())
+            },
+        };
+
+        match self {
+            PreTokenizerJson::Sequence { pretokenizers } => {
+                for pretokenizer in pretokenizers {
+                    pretokenizer.apply_to(pattern, split_digits, byte_level)?;
+                }
+            },
+            PreTokenizerJson::Split { pattern: split, behavior, invert: false } if behavior == "Isolated" => match split {
+                PatternJson::Regex(regex) => set_pattern(SplitPattern::Custom(regex.clone()))?,
+                PatternJson::String(string) => set_pattern(SplitPattern::Custom(regex::escape(string)))?,
+            },
+            PreTokenizerJson::Split { .. } => return Err(Error::Unsupported("Split pre-tokenizers other than Isolated".to_string())),
+            PreTokenizerJson::Digits { individual_digits: true } => *split_digits = true,
+            PreTokenizerJson::Digits { .. } => return Err(Error::Unsupported("Digits without individual_digits".to_string())),
+            PreTokenizerJson::ByteLevel { add_prefix_space: true, .. } => return Err(Error::Unsupported("add_prefix_space".to_string())),
+            PreTokenizerJson::Other => return Err(Error::Unsupported("pre-tokenizers other than Split, Digits and ByteLevel".to_string())),
+            PreTokenizerJson::ByteLevel { use_regex, .. } => {
+                if *use_regex {
+                    set_pattern(SplitPattern::Custom(GPT2_PATTERN.to_string()))?;
+                }
+                *byte_level = true;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Tokeniser {
+    // Reads a Hugging Face tokenizer.json with a BPE model. The ids, merges, normaliser, pre-tokeniser, byte level setting,
+    // special tokens and unknown token handling all come from the file
+    pub fn from_hugging_face<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::MissingVocab(path.to_path_buf()));
+        }
+
+        let file: TokenizerJson = serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map_err(|e| Error::malformed(path.display(), e))?;
+        Self::from_tokenizer_json(file)
+    }
+
+    pub fn from_hugging_face_json(json: &str) -> Result<Self> {
+        let file: TokenizerJson = serde_json::from_str(json).map_err(|e| Error::malformed("JSON string", e))?;
+        Self::from_tokenizer_json(file)
+    }
+
+    fn from_tokenizer_json(file: TokenizerJson) -> Result<Self> {
+        let model = file.model;
+        if model.kind != "BPE" {
+            return Err(Error::Unsupported(format!("{} models, only BPE", model.kind)));
+        }
+        if model.dropout.is_some_and(|dropout| dropout > 0.0) {
+            return Err(Error::Unsupported("BPE dropout".to_string()));
+        }
+        if model.continuing_subword_prefix.is_some_and(|prefix| !prefix.is_empty()) || model.end_of_word_suffix.is_some_and(|suffix| !suffix.is_empty()) {
+            return Err(Error::Unsupported("subword prefixes and suffixes".to_string()));
+        }
+        if model.ignore_merges {
+            return Err(Error::Unsupported("ignore_merges".to_string()));
+        }
+
+        // the model vocab and the added tokens share one id space
+        let mut by_id: HashMap<usize, String> = HashMap::new();
+        let ids = model.vocab.into_iter().map(|(token, id)| (id, token));
+        for (id, token) in ids.chain(file.added_tokens.iter().map(|added| (added.id, added.content.clone()))) {
+            match by_id.get(&id) {
+                Some(existing) if *existing != token => {
+                    return Err(Error::Unsupported(format!("id {} is used for both {:?} and {:?}", id, existing, token)));
+                },
+                _ => by_id.insert(id, token),
+            };
+        }
+        let mut tokens = Vec::with_capacity(by_id.len());
+        for id in 0..by_id.len() {
+            tokens.push(by_id.remove(&id).ok_or_else(|| Error::Unsupported(format!("vocab ids with gaps, there's no id {}", id)))?);
+        }
+
+        let mut merges = Vec::with_capacity(model.merges.len());
+        for (rank, merge) in model.merges.into_iter().enumerate() {
+            let (left, right) = match merge {
+                MergeJson::Pair(left, right) => (left, right),
+                MergeJson::Joined(joined) => match joined.split_once(' ') {
+                    Some((left, right)) => (left.to_string(), right.to_string()),
+                    None => return Err(Error::Unsupported(format!("merge {:?} isn't two tokens", joined))),
+                },
+            };
+            merges.push(Merge { merged: format!("{}{}", left, right), left, right, rank, count: 0 });
+        }
+
+        let normaliser = match &file.normalizer {
+            Some(normalizer) => normalizer.to_normaliser()?,
+            None => Normaliser::none(),
+        };
+
+        let (mut pattern, mut split_digits, mut byte_level) = (None, false, false);
+        if let Some(pre_tokenizer) = &file.pre_tokenizer {
+            pre_tokenizer.apply_to(&mut pattern, &mut split_digits, &mut byte_level)?;
+        }
+        let pre_tokeniser = PreTokeniser::new(pattern.unwrap_or_default())?.with_split_digits(split_digits);
+
+        let specials: Vec<&str> = file.added_tokens.iter().filter(|added| added.special).map(|added| added.content.as_str()).collect();
+        let mut tokeniser = Tokeniser::from_tokens(tokens, merges)
+            .with_normaliser(normaliser)
+            .with_byte_level(byte_level)
+            .with_pre_tokeniser(pre_tokeniser)
+            .with_special_tokens(&specials);
+
+        if model.byte_fallback {
+            let first = tokeniser.token_id("<0x00>");
+            let contiguous = first.is_some_and(|first| (0..=255u8).all(|byte| tokeniser.token_id(&format!("<0x{:02X}>", byte)) == Some(first + byte as usize)));
+            if !contiguous {
+                return Err(Error::Unsupported("byte_fallback without <0x00> to <0xFF> in order".to_string()));
+            }
+            tokeniser = tokeniser.with_unknown_policy(UnknownPolicy::Bytes);
+        } else if let Some(unknown) = model.unk_token {
+            if unknown != UNKNOWN_TOKEN {
+                return Err(Error::Unsupported(format!("unk_token {:?}, only {:?}", unknown, UNKNOWN_TOKEN)));
+            }
+            tokeniser = tokeniser.with_unknown_policy(UnknownPolicy::Token);
+        }
+
+        Ok(tokeniser)
+    }
+
+    // The model as a Hugging Face tokenizer.json, with merges written as pairs so tokens containing spaces survive
+    pub fn to_hugging_face_json(&self) -> Result<String> {
+        let specials: HashMap<usize, &String> = self.special_tokens().iter().map(|(token, &id)| (id, token)).collect();
+        let mut added_tokens: Vec<AddedToken> = specials.iter()
+            .map(|(&id, &token)| AddedToken {
+                id,
+                content: token.clone(),
+                single_word: false,
+                lstrip: false,
+                rstrip: false,
+                normalized: false,
+                special: true,
+            })
+            .collect();
+        added_tokens.sort_by_key(|added| added.id);
+
+        let vocab: BTreeMap<String, usize> = self.tokens().iter()
+            .enumerate()
+            .filter(|(id, _)| !specials.contains_key(id))
+            .map(|(id, token)| (token.clone(), id))
+            .collect();
+
+        // without a decoder Hugging Face joins tokens with spaces, so the others need fusing back together
+        let decoder = if self.is_byte_level() {
+            json!({ "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": false, "use_regex": false })
+        } else if self.unknown_policy() == UnknownPolicy::Bytes {
+            json!({ "type": "Sequence", "decoders": [{ "type": "ByteFallback" }, { "type": "Fuse" }] })
+        } else {
+            json!({ "type": "Fuse" })
+        };
+
+        let file = TokenizerJson {
+            version: "1.0".to_string(),
+            truncation: None,
+            padding: None,
+            added_tokens,
+            normalizer: NormalizerJson::from_normaliser(self.normaliser()),
+            pre_tokenizer: PreTokenizerJson::from_config(self.pre_tokeniser(), self.is_byte_level()),
+            post_processor: None,
+            decoder: Some(decoder),
+            model: ModelJson {
+                kind: "BPE".to_string(),
+                dropout: None,
+                unk_token: (self.unknown_policy() == UnknownPolicy::Token).then(|| UNKNOWN_TOKEN.to_string()),
+                continuing_subword_prefix: None,
+                end_of_word_suffix: None,
+                fuse_unk: false,
+                byte_fallback: self.unknown_policy() == UnknownPolicy::Bytes,
+                ignore_merges: false,
+                vocab,
+                merges: self.merges().iter().map(|merge| MergeJson::Pair(merge.left.clone(), merge.right.clone())).collect(),
+            },
+        };
+
+        serde_json::to_string_pretty(&file).map_err(|e| Error::malformed("tokenizer.json", e))
+    }
+
+    pub fn save_hugging_face<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(self.to_hugging_face_json()?.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokeniser::END_OF_TEXT;
+
+    const FIXTURE: &str = include_str!("../fixtures/tokenizer.json");
+
+    #[test]
+    fn imports_and_round_trips_a_fixture() {
+        let tokeniser = Tokeniser::from_hugging_face_json(FIXTURE).unwrap().with_allow_special(true);
+        assert_eq!(tokeniser.vocab_size(), 16);
+        assert_eq!(tokeniser.merges().len(), 6);
+        assert_eq!(tokeniser.normaliser(), Normaliser { lowercase: true, ..Normaliser::none() });
+        assert_eq!(tokeniser.unknown_policy(), UnknownPolicy::Token);
+        assert_eq!(tokeniser.special_token_id(END_OF_TEXT), Some(15));
+
+        // hello + " " + wor + l + d + <unk> for the ! + <|endoftext|>
+        let input = "Hello World!<|endoftext|>";
+        assert_eq!(tokeniser.encode(input).unwrap(), vec![12, 1, 14, 5, 2, 0, 15]);
+
+        let exported = tokeniser.to_hugging_face_json().unwrap();
+        let decoder = serde_json::from_str::<Value>(&exported).unwrap()["decoder"].clone();
+        assert_eq!(decoder, json!({ "type": "Fuse" })); // not null, or Hugging Face decodes with spaces between tokens
+        let reimported = Tokeniser::from_hugging_face_json(&exported).unwrap().with_allow_special(true);
+        assert_eq!(reimported.tokens(), tokeniser.tokens());
+        assert_eq!(reimported.merges(), tokeniser.merges());
+        assert_eq!(reimported.normaliser(), tokeniser.normaliser());
+        assert_eq!(reimported.unknown_policy(), tokeniser.unknown_policy());
+        assert_eq!(reimported.special_tokens(), tokeniser.special_tokens());
+        assert_eq!(reimported.encode(input).unwrap(), tokeniser.encode(input).unwrap());
+        assert_eq!(reimported.to_hugging_face_json().unwrap(), exported);
+    }
+
+    #[test]
+    fn round_trips_pre_tokenisers() {
+        let byte_level = FIXTURE.replace(r#""pre_tokenizer": null"#, r#""pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true }"#);
+        let tokeniser = Tokeniser::from_hugging_face_json(&byte_level).unwrap();
+        assert!(tokeniser.is_byte_level());
+        assert_eq!(*tokeniser.pre_tokeniser(), PreTokeniser::gpt2());
+
+        let for_digits = tokeniser.with_pre_tokeniser(PreTokeniser::cl100k().with_split_digits(true));
+        for tokeniser in [Tokeniser::from_hugging_face_json(&byte_level).unwrap(), for_digits] {
+            let reimported = Tokeniser::from_hugging_face_json(&tokeniser.to_hugging_face_json().unwrap()).unwrap();
+            assert_eq!(reimported.config(), tokeniser.config());
+            assert_eq!(serde_json::from_str::<Value>(&tokeniser.to_hugging_face_json().unwrap()).unwrap()["decoder"]["type"], "ByteLevel");
+            assert_eq!(reimported.encode("hello world 2024").unwrap(), tokeniser.encode("hello world 2024").unwrap());
+        }
+
+        let split = FIXTURE.replace(r#""pre_tokenizer": null"#, r#""pre_tokenizer": { "type": "Sequence", "pretokenizers": [
+            { "type": "Split", "pattern": { "String": " " }, "behavior": "Isolated", "invert": false },
+            { "type": "Digits", "individual_digits": true }
+        ] }"#);
+        let tokeniser = Tokeniser::from_hugging_face_json(&split).unwrap();
+        assert_eq!(*tokeniser.pre_tokeniser().pattern(), SplitPattern::Custom(" ".to_string()));
+        assert!(tokeniser.pre_tokeniser().splits_digits());
+
+        let removed = split.replace("Isolated", "Removed");
+        assert!(matches!(Tokeniser::from_hugging_face_json(&removed), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn rejects_what_it_cant_run() {
+        let wordpiece = FIXTURE.replace(r#""type": "BPE""#, r#""type": "WordPiece""#);
+        assert!(matches!(Tokeniser::from_hugging_face_json(&wordpiece), Err(Error::Unsupported(_))));
+
+        let prepend = FIXTURE.replace(r#"{ "type": "Lowercase" }"#, r#"{ "type": "Prepend", "prepend": "_" }"#);
+        assert!(matches!(Tokeniser::from_hugging_face_json(&prepend), Err(Error::Unsupported(_))));
+        let metaspace = FIXTURE.replace(r#""pre_tokenizer": null"#, r#""pre_tokenizer": { "type": "Metaspace", "replacement": "▁" }"#);
+        assert!(matches!(Tokeniser::from_hugging_face_json(&metaspace), Err(Error::Unsupported(_))));
+        let strip_accents = FIXTURE.replace(r#"{ "type": "Lowercase" }"#, r#"{ "type": "StripAccents" }"#);
+        assert!(matches!(Tokeniser::from_hugging_face_json(&strip_accents), Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,27 @@
+//! A Byte Pair Encoder: train a vocabulary with [`Trainer`], then encode and decode text with [`Tokeniser`].
+//! [`Tokeniser::encoding`] gives an [`Encoding`] with every token's id and where in the input it came from.
+//! Batches are truncated and padded into fixed length model input with the settings in [`model_input`].
+//! Both normalise the input and split it into pieces with a [`PreTokeniser`] first, as set out in a [`ModelConfig`].
+//! Vocabularies and merge lists are read and written with the functions in [`vocab`], encoded ids with [`token_file`].
+//! Hugging Face `tokenizer.json` and tiktoken `.tiktoken` files are loaded and saved with the methods in [`hugging_face`] and [`tiktoken`].
+
+pub mod byte_level;
+pub mod encoding;
+pub mod error;
+pub mod hugging_face;
+pub mod model_input;
+pub mod normaliser;
+pub mod pre_tokeniser;
+pub mod tiktoken;
+pub mod token_file;
+pub mod tokeniser;
+pub mod trainer;
+pub mod vocab;
+
+pub use encoding::Encoding;
+pub use error::{Error, Result};
+pub use model_input::{ModelInput, Padding, Truncation};
+pub use normaliser::Normaliser;
+pub use pre_tokeniser::PreTokeniser;
+pub use tokeniser::{Merge, ModelConfig, TokenOffsets, Tokeniser, UnencodableInput, UnknownPolicy};
+pub use trainer::Trainer;
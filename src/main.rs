@@ -1,303 +1,439 @@
-#![allow(unused)]
-
 // My goal is to implement a Byte Pair Encoder in Rust
-// Currently only handle lower case, unpunctuated data.
+// The command line front end: training, encoding and decoding with any vocab (bundled, trained, Hugging Face or
+// tiktoken), plus the visualiser
 
-use std::fs::{self, File};
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::Path;
-use std::{io, time};
-use rayon::prelude::*;
-use std::io::{Read, Write};
-use serde_json;
-use std::io::{BufRead, BufReader};
 use std::time::Instant;
 
-use crate::tokeniser::Tokeniser;
-use crate::visualiser::run;
+use rs_tokeniser::normaliser::UnicodeForm;
+use rs_tokeniser::token_file::{self, Header, TokenReader, TokenWriter};
+use rs_tokeniser::tokeniser::{bundled_vocabularies, config_path_for, merges_path_for};
+use rs_tokeniser::vocab::{initialize_vocab, read_piece_counts, read_word_counts, read_words, save_config, save_merges, save_vocabulary};
+use rs_tokeniser::{byte_level, ModelConfig, Normaliser, PreTokeniser, Tokeniser, Trainer};
 
-mod tokeniser;
+#[cfg(feature = "gui")]
 mod visualiser;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut visualiser = visualiser::run();
-    
-    println!("Reading file...");
-    let filename = "src/text8.txt"; // 124_301_826 words
-    
-    /*
-        let file_content = fs::read_to_string(filename)?;
-    let mut contents: Vec<String> = file_content // need to have a Vec<String>, where each string is a single character.
-        .chars()
-        .map(|c| c.to_string())
-        .filter(|f| f != "\n")
-        .collect();
-    */
-
-    
-
-    //let contents = _read_words(&filename, 120_301_826);
-    //println!("file read.");
-
-
-    let text = "the quick brown fox jumped over the lazy dog and that was just the beginning of the tale 
-        it told of its adventures throughout the forest the fox always loved to explore and discover new places and 
-        today was no different as it made its way through the underbrush it came across many other creatures some 
-        were fast and others were slow but all were a part of the vibrant ecosystem that the fox called home the sun was 
-        high in the sky casting a warm glow over the land as the fox continued its journey it thought about the many days it had spent 
-        roaming this terrain each day brought new surprises and challenges that kept the fox on its toes and as the light began to fade the fox found 
-        its way back to its den settled in for the night and dreamed of the next days adventures";
-
-    let other_text = "the zebra found a xylophone one sunny day when wandering near the edge of the meadow curious about the 
-        strange object with colorful bars the zebra tapped on it gently with its hoof the sound that came out was magical and unlike 
-        anything it had ever heard before delighted the zebra played a tune although it didnt know exactly how to play music it managed 
-        to make a joyful melody that echoed throughout the savannah other animals gathered around drawn by the unique sounds even the birds 
-        paused their singing to listen the zebra felt a surge of happiness as it shared this new discovery with its friends and as the sun set the zebra 
-        knew it had found a new way to express its joy and creativity through the enchanting sounds of the xylophone"
-            .replace("\n", "");
-
-    /*
-        let contents: Vec<String> = text
-        .chars()
-        .map(|c| c.to_string())
-        .filter(|f| f != "\n")
-        .collect();
-    */
-
-    //run();
-
-    /*
-        println!("Size of contents: {} bytes", std::mem::size_of_val(&contents));
-    let initial_vocab_path = "output/initial_vocab.json";
-    
-    if !Path::new(initial_vocab_path).exists() {
-        let initial_vocab = initialize_vocab(&contents);
-        save_initial_vocab(&initial_vocab, initial_vocab_path)?;
+const USAGE: &str = "Usage: rs-tokeniser <command> [options]
+
+Commands:
+  train    --input <file> --vocab-size <n> --out <vocab.json> [--words <limit>] [--stream] [--byte-level]
+           [--pre-tokeniser <whitespace, gpt2 or cl100k>] [--split-digits] [--normalise <steps>]
+           Trains on the word counts of the input (or every character in order with --stream, or every byte
+           with --byte-level), or on the pieces --pre-tokeniser splits it into. Text is lowercased with newlines
+           removed unless --normalise says otherwise (byte level text isn't normalised at all). The merges are
+           saved next to the vocab as <name>.merges.json, and how the input was prepared as <name>.config.json
+  encode   [--vocab <file or bundled name>] [--byte-level] [--lossless] [--binary]
+           Reads text from stdin and prints the token ids, or writes them as a binary token file with --binary.
+           --lossless encodes the text exactly as given, using byte tokens for anything the vocab doesn't have.
+           --byte-level is the same for a vocab trained with --byte-level that was saved without its config
+  decode   [--vocab <file or bundled name>] [--byte-level] [--lossless]
+           Reads token ids (as text or a binary token file) from stdin and prints the text. Takes the same
+           --byte-level or --lossless the ids were encoded with
+  inspect  [--vocab <file or bundled name>]
+           Prints stats about a vocabulary
+  export   [--vocab <file or bundled name>] --format <huggingface or tiktoken> --out <file>
+           Saves the vocabulary in another tokeniser's format
+  bench    [--vocab <file or bundled name>] [--input <file>] [--out <file>]
+           Times tokenising --input and saves the ids to --out as a binary token file. Built with
+           --features bench it first times the sliding window encoder against the matcher
+  gui      [--vocab <file or bundled name>]
+           Opens the visualiser (built with --features gui)
+
+Without --vocab, output/vocabulary.json is used if it's been trained, otherwise the default bundled vocabulary.
+--vocab can also be a Hugging Face tokenizer.json or a .tiktoken file. Every command that loads a vocab also takes
+--pre-tokeniser <none, whitespace, gpt2 or cl100k> and --split-digits to override how it splits its input
+(.tiktoken files don't say, cl100k_base wants --pre-tokeniser cl100k), and --normalise to override how it's normalised.
+--normalise is a comma separated list of nfc, nfkc, strip-accents, lowercase, strip-newlines and collapse-whitespace,
+or none or text8.
+Bundled vocabularies: ";
+
+// bench compares the encoders on these, one text per line
+#[cfg(feature = "bench")]
+const SAMPLE_TEXTS: &str = include_str!("../fixtures/sample.txt");
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = Options::parse(args.get(1..).unwrap_or_default());
+
+    let result = match args.first().map(String::as_str) {
+        Some("train") => train(&options),
+        Some("encode") => encode(&options),
+        Some("decode") => decode(&options),
+        Some("inspect") => inspect(&options),
+        Some("export") => export(&options),
+        Some("bench") => bench(&options),
+        Some("gui") => gui(&options),
+        Some("help" | "--help" | "-h") | None => {
+            print_usage();
+            Ok(())
+        },
+        Some(other) => Err(format!("unknown command {:?}, run with --help to see the commands", other).into()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
     }
-    
+}
 
-    let initial_vocab = load_initial_vocab(initial_vocab_path)?;
-    let (vocab, _tokenized_string) = bpe(contents, 10000, initial_vocab);
+fn print_usage() {
+    let bundled = bundled_vocabularies().join(", ");
+    println!("{}{}", USAGE, bundled);
+}
 
-    save_vocabulary(&vocab, "output/vocabulary.json")?;
+// The options every command that loads a vocab takes, see Options::tokeniser()
+const VOCAB_OPTIONS: [&str; 6] = ["vocab", "byte-level", "lossless", "pre-tokeniser", "split-digits", "normalise"];
 
-    let mut tokenizer = Tokeniser::new().unwrap();
-    let before = Instant::now();
-    for _ in 0..10 {
-        tokenizer.tokenise(&text.to_string());
-    }
-    
-    tokenizer.pretty_print();
-    println!("\n");
-    for _ in 0..10 {
-        tokenizer.tokenise(&other_text.to_string());
-    }
-    tokenizer.pretty_print();
-    println!("Elapsed time: {:.2?}", before.elapsed());
-     */
+// --name value pairs. Flags without a value (--stream) are stored with an empty one
+struct Options {
+    values: HashMap<String, String>,
+    stray: Vec<String>, // anything that isn't an option or its value
+}
 
-    let mut tokeniser = Tokeniser::new().unwrap();
-    let read_start = Instant::now();
+impl Options {
+    fn parse(args: &[String]) -> Self {
+        let mut values = HashMap::new();
+        let mut stray = Vec::new();
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = args.next_if(|value| !value.starts_with("--")).cloned().unwrap_or_default();
+                values.insert(name.to_string(), value);
+            } else {
+                stray.push(arg.clone());
+            }
+        }
+        Options { values, stray }
+    }
 
-    println!("reading string");
-    let initial = _read_file_to_string(filename).unwrap();
-    println!("Time to read file: {:.2?}", read_start.elapsed());
+    // Errors on anything the command doesn't take, so a misspelt option (--vocabulary) isn't silently ignored
+    fn accept(&self, names: &[&str]) -> Result<(), String> {
+        if let Some(arg) = self.stray.first() {
+            return Err(format!("unexpected argument {:?}, options start with --", arg));
+        }
+        let mut unknown: Vec<&String> = self.values.keys().filter(|name| !names.contains(&name.as_str())).collect();
+        unknown.sort_unstable();
+        match unknown.first() {
+            Some(name) => Err(format!("unknown option --{}, run with --help to see the options", name)),
+            None => Ok(()),
+        }
+    }
 
-    println!("starting");
-    let starting_time = Instant::now();
-    let tokens = tokeniser.get_tokens_from_text(&initial);
-    let tokenising_time = Instant::now();
-    println!("Time to tokenise: {:.2?}", starting_time.elapsed());
-    println!("tokenised.");
-    let text = tokeniser.reconstruct(&tokens);
-    let reconstruct_time = Instant::now();
-    println!("Time to reconstruct: {:.2?}", tokenising_time.elapsed());
-    //println!("{:?}", tokens);
-    //println!("{:?}", text);
-    println!("reconstructed.");
+    // accept() for a command that loads a vocab, so it takes VOCAB_OPTIONS as well as these
+    fn accept_with_vocab(&self, names: &[&str]) -> Result<(), String> {
+        self.accept(&[&VOCAB_OPTIONS[..], names].concat())
+    }
 
-    println!("It took {:?} to tokenise, and {:?} to reconstruct for a total of {:?}", tokenising_time.duration_since(starting_time), reconstruct_time.duration_since(tokenising_time), reconstruct_time.duration_since(starting_time));
-    
-    _write_string_to_file("tokenised.txt", &format!("{:?}", tokens));
-    Ok(())
-}
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str).filter(|value| !value.is_empty())
+    }
 
+    fn require(&self, name: &str) -> Result<&str, String> {
+        self.get(name).ok_or_else(|| format!("missing --{}", name))
+    }
 
-fn bpe(mut corpus: Vec<String>, vocab_size: usize, initial_vocab: HashMap<String, i32>) -> (HashMap<String, i32>, Vec<String>) {
-    println!("Beginning BPE process");
+    fn number(&self, name: &str) -> Result<Option<usize>, String> {
+        match self.get(name) {
+            Some(value) => value.parse().map(Some).map_err(|_| format!("--{} should be a number, not {:?}", name, value)),
+            None => Ok(None),
+        }
+    }
 
-    let mut vocab = initial_vocab;
-    let mut pair_count;
-    let mut count = 0;
-    //println!("Initial Vocab: {:?}", vocab);  // Debug print statement
+    fn flag(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
 
-    let corpus_ptr = &mut corpus as *mut Vec<String>; // Get a raw pointer to corpus to sidepass the borrow checker
+    // --pre-tokeniser by name (or current without one), with --split-digits turned on over the top
+    fn pre_tokeniser(&self, current: &PreTokeniser) -> Result<PreTokeniser, String> {
+        let pre_tokeniser = match self.get("pre-tokeniser") {
+            None => current.clone(),
+            Some("none") => PreTokeniser::none(),
+            Some("whitespace") => PreTokeniser::whitespace(),
+            Some("gpt2") => PreTokeniser::gpt2(),
+            Some("cl100k") => PreTokeniser::cl100k(),
+            Some(other) => return Err(format!("unknown pre-tokeniser {:?}, expected none, whitespace, gpt2 or cl100k", other)),
+        };
+        Ok(if self.flag("split-digits") { pre_tokeniser.with_split_digits(true) } else { pre_tokeniser })
+    }
 
-    while vocab.len() < vocab_size {
-        pair_count = count_adjacent_pairs(&corpus);
-        //println!("pair_count: {:?}", pair_count);
-        
-        if let Some(best_pair) = find_most_frequent_pair(&pair_count) {
-            println!("Merging \"{}\" \"{}\"", best_pair.0, best_pair.1);
-            unsafe { // TODO: -- restructure this in the future! --
-                merge_pair(best_pair, &mut vocab, &mut *corpus_ptr); // raw pointer shenanigans
+    // --normalise as a comma separated list of steps. None if it wasn't given
+    fn normaliser(&self) -> Result<Option<Normaliser>, String> {
+        let Some(steps) = self.get("normalise") else {
+            return Ok(None);
+        };
+
+        let mut normaliser = Normaliser::none();
+        for step in steps.split(',').map(str::trim) {
+            match step {
+                "none" => {},
+                "text8" => normaliser = Normaliser { lowercase: true, strip_newlines: true, ..normaliser },
+                "nfc" => normaliser.form = Some(UnicodeForm::Nfc),
+                "nfkc" => normaliser.form = Some(UnicodeForm::Nfkc),
+                "strip-accents" => normaliser.strip_accents = true,
+                "lowercase" => normaliser.lowercase = true,
+                "strip-newlines" => normaliser.strip_newlines = true,
+                "collapse-whitespace" => normaliser.collapse_whitespace = true,
+                other => return Err(format!("unknown normalisation step {:?}, run with --help to see them", other)),
             }
-        } else {
-            println!("No best pair found"); // most likely indicates an error
-            break;
         }
+        Ok(Some(normaliser))
+    }
+
+    // --vocab is either a path to a vocabulary or the name of a bundled one
+    fn tokeniser(&self) -> Result<Tokeniser, Box<dyn std::error::Error>> {
+        let tokeniser = match self.get("vocab") {
+            Some(vocab) if vocab.ends_with("tokenizer.json") => Tokeniser::from_hugging_face(vocab)?, // brings its own normaliser
+            Some(vocab) if vocab.ends_with(".tiktoken") => Tokeniser::from_tiktoken(vocab)?,
+            Some(vocab) if config_path_for(Path::new(vocab)).exists() => Tokeniser::from_file(vocab)?, // brings its own config
+            Some(vocab) if Path::new(vocab).exists() => Tokeniser::from_file(vocab)?.with_normaliser(Normaliser::text8()), // trained before configs were saved, on lowercased input
+            Some(name) => Tokeniser::bundled(name)?,
+            None => Tokeniser::new()?,
+        };
+        let pre_tokeniser = self.pre_tokeniser(tokeniser.pre_tokeniser())?;
+        let tokeniser = tokeniser.with_pre_tokeniser(pre_tokeniser);
+
+        let tokeniser = if !self.flag("byte-level") { // tiktoken and some Hugging Face files are byte level already
+            if self.flag("lossless") { tokeniser.with_lossless() } else { tokeniser }
+        } else if tokeniser.is_byte_level() || tokeniser.has_byte_symbols() { // a byte level vocab saved before configs were
+            tokeniser.with_byte_level(true).with_lossless()
+        } else {
+            return Err("--byte-level needs a vocab trained with --byte-level, use --lossless to keep everything with this one".into());
+        };
+
+        Ok(match self.normaliser()? {
+            Some(normaliser) => tokeniser.with_normaliser(normaliser),
+            None => tokeniser,
+        })
+    }
+}
 
-        count += 1;
+fn train(options: &Options) -> CliResult {
+    options.accept(&["input", "vocab-size", "out", "words", "stream", "byte-level", "pre-tokeniser", "split-digits", "normalise"])?;
+    let input = options.require("input")?;
+    let vocab_size = options.number("vocab-size")?.ok_or("missing --vocab-size")?;
+    let out = options.require("out")?;
+    let word_limit = options.number("words")?.unwrap_or(usize::MAX);
+
+    let byte_level = options.flag("byte-level");
+    let normaliser = options.normaliser()?;
+
+    // what's recorded in the config has to be what the corpus readers below do to the input
+    let mut config = ModelConfig {
+        normaliser: normaliser.unwrap_or(if byte_level { Normaliser::none() } else { Normaliser::text8() }),
+        pre_tokeniser: PreTokeniser::none(),
+        byte_level,
+    };
+
+    let start = Instant::now();
+    let mut trainer = if options.get("pre-tokeniser").is_some() || (normaliser.is_some() && !byte_level) {
+        config.pre_tokeniser = options.pre_tokeniser(&PreTokeniser::whitespace())?; // word counts are whitespace pieces
+        if options.flag("stream") {
+            return Err("--stream trains across the whole input as text8, it can't be used with --pre-tokeniser or --normalise".into());
+        }
+        if config.pre_tokeniser.pattern().source().is_none() {
+            return Err("--pre-tokeniser none would make the whole input one piece, use --stream or --byte-level instead".into());
+        }
 
-        if count % 50 == 0  {
-            println!("Iterations: {}, Vocab Size: {}", count, vocab.len());
+        // like word counts, only the unique pieces are kept in memory
+        let piece_counts = read_piece_counts(input, word_limit, &config)?;
+        let trainer = Trainer::from_word_counts(&piece_counts);
+        if byte_level { trainer.with_alphabet(&byte_level::alphabet()) } else { trainer }
+    } else if options.flag("split-digits") {
+        return Err("--split-digits only works with a --pre-tokeniser".into());
+    } else if byte_level {
+        let mut bytes = fs::read(input)?;
+        if !config.normaliser.is_identity() {
+            bytes = config.normaliser.normalise(&String::from_utf8_lossy(&bytes)).into_owned().into_bytes();
         }
+        Trainer::new(byte_level::corpus(&bytes), HashMap::new()).with_alphabet(&byte_level::alphabet())
+    } else if options.flag("stream") {
+        let contents = read_words(input, word_limit)?;
+        let initial_vocab = initialize_vocab(&contents);
+        Trainer::new(contents, initial_vocab)
+    } else {
+        // only the unique words are kept in memory, which is what makes the whole of text8 trainable.
+        // Each word keeps the whitespace after it, the same pieces the whitespace pre-tokeniser gives
+        config.pre_tokeniser = PreTokeniser::whitespace();
+        let word_counts = read_word_counts(input, word_limit)?;
+        Trainer::from_word_counts(&word_counts)
+    };
+
+    trainer.train_with_progress(vocab_size, |trainer| {
+        if trainer.merges().len().is_multiple_of(50) {
+            eprintln!("Iterations: {}, Vocab Size: {}", trainer.merges().len(), trainer.vocab().len());
+        }
+    })?;
+    let (vocab, merges, _) = trainer.into_parts();
+    if vocab.len() < vocab_size {
+        eprintln!("Ran out of pairs to merge at {} tokens", vocab.len());
     }
 
-    //println!("Finished iterations");
-    //println!("Count: {}", count);
-    //println!("Vocabulary: {:?}", vocab);
-    println!("Tokenized Data: {:?}", corpus);
+    let merges_path = merges_path_for(Path::new(out));
+    let config_path = config_path_for(Path::new(out));
+    save_vocabulary(&vocab, out)?;
+    save_merges(&merges, &merges_path)?;
+    save_config(&config, &config_path)?;
 
-    (vocab, corpus)
+    println!("Trained {} tokens and {} merges in {:.2?}", vocab.len(), merges.len(), start.elapsed());
+    println!("Saved {}, {} and {}", out, merges_path.display(), config_path.display());
+    Ok(())
 }
 
-fn count_adjacent_pairs(tokens: &[String]) -> HashMap<(String, String), i32> {
-    // Estimate the capacity to reduce rehashing
-    tokens.par_windows(2)
-        .fold(
-            || HashMap::new(),
-            |mut local_map, window| {
-                let token1 = &window[0];
-                let token2 = &window[1];
-                *local_map.entry((token1.clone(), token2.clone())).or_insert(0) += 1;
-                local_map
-            }
-        )
-        .reduce(
-            || HashMap::new(),
-            |mut acc, elem| {
-                for (key, value) in elem {
-                    *acc.entry(key).or_insert(0) += value;
-                }
-                acc
-            }
-        )
+fn encode(options: &Options) -> CliResult {
+    options.accept_with_vocab(&["binary"])?;
+    let tokeniser = options.tokeniser()?;
+    let stdout = BufWriter::new(io::stdout().lock());
+    if options.flag("binary") {
+        tokeniser.encode_stream_binary(io::stdin().lock(), stdout)?;
+    } else {
+        tokeniser.encode_stream(io::stdin().lock(), stdout)?; // stdin is read a chunk at a time, so text8 sized input is fine
+    }
+    Ok(())
 }
 
-fn find_most_frequent_pair(pair_count: &HashMap<(String, String), i32>) -> Option<(String, String)> {
-    pair_count.par_iter()
-    .max_by_key(|&(_, &count)| count)
-    .map(|(pair, _)| pair.clone())
+fn decode(options: &Options) -> CliResult {
+    options.accept_with_vocab(&[])?;
+    let tokeniser = options.tokeniser()?;
+
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+
+    let tokens = if input.starts_with(&token_file::MAGIC) {
+        let reader = TokenReader::new(input.as_slice())?;
+        reader.header().check(&tokeniser)?;
+        reader.read_to_end()?
+    } else {
+        // ids can be separated by whitespace or commas, so the output of encode or a pasted list both work
+        String::from_utf8_lossy(&input)
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse().map_err(|_| format!("{:?} is not a token id", id)))
+            .collect::<Result<Vec<usize>, String>>()?
+    };
+
+    let bytes = tokeniser.reconstruct_bytes(&tokens)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    stdout.write_all(&bytes)?;
+    stdout.flush()?;
+    Ok(())
 }
 
-fn merge_pair(pair: (String, String), vocab: &mut HashMap<String, i32>, data: &mut Vec<String>) {
-    let new_token = format!("{}{}", pair.0, pair.1);
+fn inspect(options: &Options) -> CliResult {
+    options.accept_with_vocab(&[])?;
+    let tokeniser = options.tokeniser()?;
+    let tokens = tokeniser.tokens();
+
+    let mut lengths: Vec<usize> = tokens.iter().map(|token| token.chars().count()).collect();
+    lengths.sort_unstable();
+    let mean = lengths.iter().sum::<usize>() as f64 / lengths.len().max(1) as f64;
+
+    println!("Tokens: {}", tokens.len());
+    println!("Merges: {}", tokeniser.merges().len());
+    println!("Byte level: {}", tokeniser.is_byte_level());
+    println!("Normaliser: {:?}", tokeniser.normaliser());
+    println!("Pre-tokeniser: {:?}, split digits: {}", tokeniser.pre_tokeniser().pattern(), tokeniser.pre_tokeniser().splits_digits());
+    println!("Special tokens: {:?}", tokeniser.special_tokens().keys().collect::<Vec<_>>());
+    println!(
+        "Token length: min {}, median {}, mean {:.2}, max {}",
+        lengths.first().unwrap_or(&0),
+        lengths.get(lengths.len() / 2).unwrap_or(&0),
+        mean,
+        lengths.last().unwrap_or(&0),
+    );
+
+    println!("Tokens by length:");
+    for length in lengths.iter().copied().collect::<std::collections::BTreeSet<_>>() {
+        let count = lengths.iter().filter(|&&l| l == length).count();
+        println!("  {:>3}: {}", length, count);
+    }
 
-    let count_token1 = vocab.get(&pair.0).unwrap();
-    let count_token2 = vocab.get(&pair.1).unwrap();
+    let mut longest: Vec<&String> = tokens.iter().collect();
+    longest.sort_by_key(|token| std::cmp::Reverse(token.chars().count()));
+    let longest: Vec<String> = longest.iter().take(10).map(|token| format!("{:?}", token)).collect();
+    println!("Longest tokens: {}", longest.join(", "));
+    Ok(())
+}
 
-    vocab.insert(new_token.clone(), count_token1 + count_token2);
+fn export(options: &Options) -> CliResult {
+    options.accept_with_vocab(&["format", "out"])?;
+    let tokeniser = options.tokeniser()?;
+    let out = options.require("out")?;
 
-    let mut i = 0;
-    while i < data.len() - 1 {
-        if data[i] == pair.0 && data[i + 1] == pair.1 {
-            data[i] = new_token.clone();
-            data.remove(i + 1); // Remove the next element as it's now been merged
-        } else {
-            i += 1;
-        }
+    match options.require("format")? {
+        "huggingface" | "hf" => tokeniser.save_hugging_face(out)?,
+        "tiktoken" => tokeniser.save_tiktoken(out)?,
+        other => return Err(format!("unknown format {:?}, expected huggingface or tiktoken", other).into()),
     }
+    println!("Saved {}", out);
+    Ok(())
 }
 
-fn save_vocabulary(vocab: &HashMap<String, i32>, file_path: &str) -> io::Result<()> {
-    // Ensure the directory exists
-    let path = Path::new(file_path);
-    if let Some(dir) = path.parent() {
-        fs::create_dir_all(dir)?;
+fn bench(options: &Options) -> CliResult {
+    options.accept_with_vocab(&["input", "out"])?;
+    let tokeniser = options.tokeniser()?;
+    #[cfg(feature = "bench")]
+    for text in SAMPLE_TEXTS.lines() {
+        benchmark_encoders(&tokeniser, text)?;
     }
 
-    let file = File::create(path);
-    match file {
-        Ok(mut f) => {
-            let json = serde_json::to_string_pretty(vocab).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            f.write_all(json.as_bytes())?;
-            Ok(())
-        },
-        Err(e) => Err(e),
-    }
-}
+    let Some(input) = options.get("input") else {
+        return if cfg!(feature = "bench") { Ok(()) } else { Err("missing --input".into()) };
+    };
 
-fn initialize_vocab(data: &Vec<String>) -> HashMap<String, i32> {
-    let mut vocab = HashMap::new();
-    for char in data {
-        *vocab.entry(char.to_string()).or_insert(0) += 1;
-    }
-    vocab
-}
+    let read_start = Instant::now();
+    let initial = _read_file_to_string(input)?;
+    println!("Time to read file: {:.2?}", read_start.elapsed());
 
-fn save_initial_vocab(vocab: &HashMap<String, i32>, file_path: &str) -> io::Result<()> {
-    let path = Path::new(file_path);
-    let file = File::create(path);
-    match file {
-        Ok(mut f) => {
-            let json = serde_json::to_string_pretty(vocab).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            f.write_all(json.as_bytes())?;
-            Ok(())
-        },
-        Err(e) => Err(e),
+    let starting_time = Instant::now();
+    let tokens = tokeniser.get_tokens_from_text(&initial)?;
+    let tokenising_time = Instant::now();
+    tokeniser.reconstruct(&tokens)?;
+    let reconstruct_time = Instant::now();
+
+    println!("It took {:?} to tokenise, and {:?} to reconstruct for a total of {:?}", tokenising_time.duration_since(starting_time), reconstruct_time.duration_since(tokenising_time), reconstruct_time.duration_since(starting_time));
+
+    if let Some(out) = options.get("out") {
+        let mut writer = TokenWriter::new(BufWriter::new(File::create(out)?), Header::for_tokeniser(&tokeniser))?;
+        writer.write(&tokens)?;
+        writer.finish()?;
     }
+    Ok(())
 }
 
-fn load_initial_vocab(file_path: &str) -> io::Result<HashMap<String, i32>> {
-    let json = fs::read_to_string(file_path)?;
-    let vocab: HashMap<String, i32> = serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    Ok(vocab)
+#[cfg(feature = "gui")]
+fn gui(options: &Options) -> CliResult {
+    options.accept_with_vocab(&[])?;
+    let tokeniser = options.tokeniser()?; // load before opening the window so a missing vocab is reported instead of crashing
+    visualiser::run(tokeniser)?;
+    Ok(())
 }
 
-fn _read_words(file_path: &str, word_count: usize) -> Vec<String> {
-    // Open the file
-    let file = File::open(file_path).expect("Unable to open the file");
-    let reader = BufReader::new(file);
-    
-    // Initialize a vector to store the characters
-    let mut contents = Vec::new();
-    let mut total_words = 0;
-
-    // Iterate through lines
-    for line_result in reader.lines() {
-        let line = line_result.expect("Unable to read line");
-
-        // Split the line into words using whitespace as the delimiter
-        for word in line.split_whitespace() {
-            total_words += 1;
-
-            // Convert each word to characters and add spaces between words
-            for char in word.chars() {
-                contents.push(char.to_string().to_ascii_lowercase());
-            }
-            contents.push(" ".to_string()); // Add a space after each word
-            
-            // Stop if we've reached the desired word count
-            if total_words >= word_count {
-                break;
-            }
-        }
+#[cfg(not(feature = "gui"))]
+fn gui(_options: &Options) -> CliResult {
+    Err("this build doesn't have the visualiser, rebuild with --features gui".into())
+}
 
-        // Stop the outer loop if we've reached the desired word count
-        if total_words >= word_count {
-            break;
-        }
-    }
+// Times the sliding window encoder against the matcher on the same input and checks they cover the same text
+#[cfg(feature = "bench")]
+fn benchmark_encoders(tokeniser: &Tokeniser, text: &str) -> rs_tokeniser::Result<()> {
+    let sliding_start = Instant::now();
+    let sliding = tokeniser._get_tokens_from_text_sliding(text);
+    let sliding_time = sliding_start.elapsed();
 
-    // Remove the last space added if it exists
-    if let Some(last) = contents.last() { 
-        if last == " " {
-            contents.pop();
-        }
-    }
+    let matcher_start = Instant::now();
+    let matched = tokeniser.get_tokens_from_text(text)?;
+    let matcher_time = matcher_start.elapsed();
 
-    contents
+    println!("Sliding window: {} tokens in {:.2?}", sliding.len(), sliding_time);
+    println!("Matcher:        {} tokens in {:.2?}", matched.len(), matcher_time);
+    println!("Same tokens: {}, same reconstruction: {}", sliding == matched, tokeniser.reconstruct(&sliding)? == tokeniser.reconstruct(&matched)?);
+    Ok(())
 }
 
 fn _read_file_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
@@ -306,9 +442,3 @@ fn _read_file_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
     file.read_to_string(&mut contents)?;
     Ok(contents)
 }
-
-fn _write_string_to_file<P: AsRef<Path>>(path: P, contents: &str) -> io::Result<()> {
-    let mut file = File::create(path)?;
-    file.write_all(contents.as_bytes())?;
-    Ok(())
-}
\ No newline at end of file
@@ -0,0 +1,203 @@
+use crate::error::{Error, Result};
+
+// Which end of a sequence truncation cuts from and padding goes on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    #[default]
+    Right,
+}
+
+// Cuts sequences down to max_length tokens. What's cut off isn't lost: it comes back as overflowing windows of
+// at most max_length, each starting with the last stride tokens of the window before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncation {
+    pub max_length: usize,
+    pub stride: usize,
+    pub side: Side, // Left keeps the end of the sequence, and the overflow works back towards the start
+}
+
+impl Truncation {
+    pub fn new(max_length: usize) -> Self {
+        Truncation { max_length, stride: 0, side: Side::Right }
+    }
+
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    // Every window the ids are cut into, the one that's kept first
+    fn windows(&self, ids: &[u32]) -> Result<Vec<Vec<u32>>> {
+        if self.stride >= self.max_length {
+            return Err(Error::InvalidTruncation(format!("a stride of {} would never get past a max length of {}", self.stride, self.max_length)));
+        }
+
+        let mut ids = ids.to_vec();
+        if self.side == Side::Left { // cut the reversed ids from the right, then put every window back the right way round
+            ids.reverse();
+        }
+
+        let step = self.max_length - self.stride;
+        let mut windows = Vec::new();
+        let mut start = 0;
+        loop {
+            let end = (start + self.max_length).min(ids.len());
+            windows.push(ids[start..end].to_vec());
+            if end == ids.len() {
+                break;
+            }
+            start += step;
+        }
+
+        if self.side == Side::Left {
+            for window in windows.iter_mut() {
+                window.reverse();
+            }
+        }
+        Ok(windows)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadTo {
+    Longest, // the longest sequence in the batch, overflow included
+    Length(usize), // a fixed length. Anything already longer is left alone, truncate it to stop that
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Padding {
+    pub to: PadTo,
+    pub pad_id: u32, // usually Tokeniser::special_token_id(PAD_TOKEN)
+    pub side: Side,
+}
+
+impl Padding {
+    pub fn longest(pad_id: u32) -> Self {
+        Padding { to: PadTo::Longest, pad_id, side: Side::Right }
+    }
+
+    pub fn fixed(length: usize, pad_id: u32) -> Self {
+        Padding { to: PadTo::Length(length), pad_id, side: Side::Right }
+    }
+
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+}
+
+// One sequence ready to feed a model. The attention mask is 1 for every real token and 0 for padding
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelInput {
+    pub ids: Vec<u32>,
+    pub attention_mask: Vec<u32>,
+    pub overflowing: Vec<ModelInput>, // the windows truncation cut off, padded the same way. Always empty in these
+}
+
+impl ModelInput {
+    fn new(ids: Vec<u32>) -> Self {
+        let attention_mask = vec![1; ids.len()];
+        ModelInput { ids, attention_mask, overflowing: Vec::new() }
+    }
+
+    fn pad(&mut self, length: usize, padding: &Padding) {
+        let missing = length.saturating_sub(self.ids.len());
+        match padding.side {
+            Side::Right => {
+                self.ids.extend(std::iter::repeat_n(padding.pad_id, missing));
+                self.attention_mask.extend(std::iter::repeat_n(0, missing));
+            },
+            Side::Left => {
+                self.ids.splice(0..0, std::iter::repeat_n(padding.pad_id, missing));
+                self.attention_mask.splice(0..0, std::iter::repeat_n(0, missing));
+            },
+        }
+    }
+}
+
+// Truncates and then pads every sequence in the batch (eg from Tokeniser::encode_batch()), with either left out
+pub fn prepare_batch(batch: &[Vec<u32>], truncation: Option<Truncation>, padding: Option<Padding>) -> Result<Vec<ModelInput>> {
+    let mut inputs = Vec::with_capacity(batch.len());
+    for ids in batch {
+        let input = match &truncation {
+            Some(truncation) => {
+                let mut windows = truncation.windows(ids)?.into_iter().map(ModelInput::new);
+                let mut input = windows.next().unwrap_or_default(); // there's always at least one window
+                input.overflowing = windows.collect();
+                input
+            },
+            None => ModelInput::new(ids.clone()),
+        };
+        inputs.push(input);
+    }
+
+    let Some(padding) = padding else {
+        return Ok(inputs);
+    };
+    let length = match padding.to {
+        PadTo::Longest => inputs.iter()
+            .flat_map(|input| std::iter::once(input).chain(&input.overflowing))
+            .map(|input| input.ids.len())
+            .max()
+            .unwrap_or(0),
+        PadTo::Length(length) => length,
+    };
+    for input in inputs.iter_mut() {
+        input.pad(length, &padding);
+        for overflow in input.overflowing.iter_mut() {
+            overflow.pad(length, &padding);
+        }
+    }
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(inputs: &[ModelInput]) -> Vec<Vec<u32>> {
+        inputs.iter().map(|input| input.ids.clone()).collect()
+    }
+
+    #[test]
+    fn truncates_with_overflow() {
+        let batch = vec![(1..=10).collect::<Vec<u32>>(), vec![1, 2]];
+
+        let right = prepare_batch(&batch, Some(Truncation::new(4).with_stride(1)), None).unwrap();
+        assert_eq!(ids(&right), [vec![1, 2, 3, 4], vec![1, 2]]);
+        assert_eq!(ids(&right[0].overflowing), [vec![4, 5, 6, 7], vec![7, 8, 9, 10]]);
+        assert!(right[1].overflowing.is_empty());
+
+        let left = prepare_batch(&batch, Some(Truncation::new(4).with_side(Side::Left)), None).unwrap();
+        assert_eq!(ids(&left), [vec![7, 8, 9, 10], vec![1, 2]]);
+        assert_eq!(ids(&left[0].overflowing), [vec![3, 4, 5, 6], vec![1, 2]]);
+
+        assert!(matches!(prepare_batch(&batch, Some(Truncation::new(4).with_stride(4)), None), Err(Error::InvalidTruncation(_))));
+        assert_eq!(ids(&prepare_batch(&[vec![]], Some(Truncation::new(4)), None).unwrap()), [Vec::<u32>::new()]);
+    }
+
+    #[test]
+    fn pads_with_a_mask() {
+        let batch = vec![vec![5, 6, 7], vec![8]];
+
+        let longest = prepare_batch(&batch, None, Some(Padding::longest(0))).unwrap();
+        assert_eq!(ids(&longest), [vec![5, 6, 7], vec![8, 0, 0]]);
+        assert_eq!(longest[1].attention_mask, [1, 0, 0]);
+
+        let fixed = prepare_batch(&batch, None, Some(Padding::fixed(4, 99).with_side(Side::Left))).unwrap();
+        assert_eq!(ids(&fixed), [vec![99, 5, 6, 7], vec![99, 99, 99, 8]]);
+        assert_eq!(fixed[0].attention_mask, [0, 1, 1, 1]);
+
+        // overflow is padded too, to the same length
+        let both = prepare_batch(&[vec![1, 2, 3, 4, 5], vec![6]], Some(Truncation::new(3)), Some(Padding::longest(0))).unwrap();
+        assert_eq!(ids(&both), [vec![1, 2, 3], vec![6, 0, 0]]);
+        assert_eq!(ids(&both[0].overflowing), [vec![4, 5, 0]]);
+        assert_eq!(both[0].overflowing[0].attention_mask, [1, 1, 0]);
+    }
+}
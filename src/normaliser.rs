@@ -0,0 +1,258 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::{canonical_combining_class, compose, decompose_canonical, decompose_compatible, is_combining_mark};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnicodeForm {
+    Nfc, // canonical composition, so e followed by a combining acute becomes the single é
+    Nfkc, // compatibility composition too, so ﬁ becomes fi and ² becomes 2
+}
+
+// Everything that's done to the input before it's matched against the vocab. Each step loses information,
+// so a tokeniser only round trips exactly when all of them are off. They run in the order they're listed here
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Normaliser {
+    pub form: Option<UnicodeForm>,
+    pub strip_accents: bool, // drop combining marks, so é becomes e
+    pub lowercase: bool, // full Unicode lowercase, a character at a time
+    pub strip_newlines: bool, // drop '\n' entirely
+    pub collapse_whitespace: bool, // every run of whitespace becomes a single space
+}
+
+impl Normaliser {
+    // Leaves the input untouched
+    pub fn none() -> Self {
+        Normaliser::default()
+    }
+
+    // What the bundled output/ vocabularies were trained on - text8 is lowercase with no newlines
+    pub fn text8() -> Self {
+        Normaliser {
+            lowercase: true,
+            strip_newlines: true,
+            ..Normaliser::none()
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Normaliser::none()
+    }
+
+    pub fn normalise<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.is_identity() {
+            return Cow::Borrowed(text);
+        }
+
+        let composed: Cow<str> = match self.form {
+            Some(UnicodeForm::Nfc) => Cow::Owned(text.nfc().collect()),
+            Some(UnicodeForm::Nfkc) => Cow::Owned(text.nfkc().collect()),
+            None => Cow::Borrowed(text),
+        };
+
+        let mut output = String::with_capacity(composed.len());
+        let mut in_whitespace = false;
+        for c in composed.chars() {
+            if self.strip_newlines && c == '\n' {
+                continue;
+            }
+            if self.collapse_whitespace && c.is_whitespace() {
+                if !in_whitespace {
+                    output.push(' ');
+                }
+                in_whitespace = true;
+                continue;
+            }
+            in_whitespace = false;
+
+            if self.strip_accents {
+                strip_accents(c, |c| self.push_case(c, &mut output));
+            } else {
+                self.push_case(c, &mut output);
+            }
+        }
+        Cow::Owned(output)
+    }
+
+    fn push_case(&self, c: char, output: &mut String) {
+        if self.lowercase {
+            output.extend(c.to_lowercase());
+        } else {
+            output.push(c);
+        }
+    }
+
+    // Whether text can be cut between these two characters and each side normalised on its own, giving the same
+    // as normalising it all at once. Always true unless composing or collapsing whitespace could join them
+    pub fn separable(&self, before: char, after: char) -> bool {
+        let (before_composed, after_composed) = match self.form {
+            Some(form) => (compose_char(before, form), compose_char(after, form)),
+            None => (before.to_string(), after.to_string()),
+        };
+
+        let whitespace = |text: &str, last: bool| if last { text.chars().last() } else { text.chars().next() }.is_some_and(char::is_whitespace);
+        if self.collapse_whitespace && whitespace(&before_composed, true) && whitespace(&after_composed, false) {
+            return false;
+        }
+
+        let Some(form) = self.form else {
+            return true;
+        };
+
+        // composition only ever joins a starter (combining class 0) to what comes after it, and a starter blocks
+        // anything after it from joining anything before. So after has to start with a starter that doesn't join
+        // what before ends with
+        let Some(first) = decompose_char(after, form).first().copied() else {
+            return true;
+        };
+        // Hangul vowels and trailing consonants join whatever syllable the jamo before them has already built
+        if canonical_combining_class(first) != 0 || matches!(first, '\u{1161}'..='\u{1175}' | '\u{11A8}'..='\u{11C2}') {
+            return false;
+        }
+        let Some(last) = decompose_char(before, form).last().copied() else {
+            return true;
+        };
+        let last_composed = before_composed.chars().last().unwrap_or(last);
+        canonical_combining_class(last) != 0 || (compose(last, first).is_none() && compose(last_composed, first).is_none())
+    }
+
+    // normalise(), along with where it came from: (normalised offset, original offset) at every point the text could be
+    // cut (see separable()), starting with (0, 0) and ending with both lengths. Everything between two of them
+    // came from everything between the matching two in the original
+    pub fn normalise_aligned(&self, text: &str) -> (String, Vec<(usize, usize)>) {
+        let mut output = String::with_capacity(text.len());
+        let mut alignment = vec![(0, 0)];
+        let mut chunk_start = 0;
+
+        let mut chars = text.char_indices().peekable();
+        while let Some((_, before)) = chars.next() {
+            let Some(&(cut, after)) = chars.peek() else {
+                break;
+            };
+            if self.separable(before, after) {
+                output.push_str(&self.normalise(&text[chunk_start..cut]));
+                alignment.push((output.len(), cut));
+                chunk_start = cut;
+            }
+        }
+        if chunk_start < text.len() {
+            output.push_str(&self.normalise(&text[chunk_start..]));
+            alignment.push((output.len(), text.len()));
+        }
+        (output, alignment)
+    }
+
+    // The last point text can be cut at where separable() holds, None if there isn't one. Never 0 or the very end
+    pub fn last_separable(&self, text: &str) -> Option<usize> {
+        let mut chars = text.char_indices().rev().peekable();
+        while let Some((cut, after)) = chars.next() {
+            let before = chars.peek()?.1;
+            if self.separable(before, after) {
+                return Some(cut);
+            }
+        }
+        None
+    }
+}
+
+fn compose_char(c: char, form: UnicodeForm) -> String {
+    let mut buffer = [0; 4];
+    let c = c.encode_utf8(&mut buffer);
+    match form {
+        UnicodeForm::Nfc => c.nfc().collect(),
+        UnicodeForm::Nfkc => c.nfkc().collect(),
+    }
+}
+
+fn decompose_char(c: char, form: UnicodeForm) -> Vec<char> {
+    let mut parts = Vec::new();
+    match form {
+        UnicodeForm::Nfc => decompose_canonical(c, |part| parts.push(part)),
+        UnicodeForm::Nfkc => decompose_compatible(c, |part| parts.push(part)),
+    }
+    parts
+}
+
+// Hands on c without its combining marks. Characters that decompose without any marks (eg Hangul) are left whole
+fn strip_accents(c: char, mut push: impl FnMut(char)) {
+    if is_combining_mark(c) {
+        return;
+    }
+
+    let mut parts = Vec::new();
+    decompose_canonical(c, |part| parts.push(part));
+    if !parts.iter().any(|&part| is_combining_mark(part)) {
+        push(c);
+        return;
+    }
+    for part in parts.into_iter().filter(|&part| !is_combining_mark(part)) {
+        push(part);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn every_step(form: UnicodeForm) -> Normaliser {
+        Normaliser {
+            form: Some(form),
+            strip_accents: true,
+            lowercase: true,
+            strip_newlines: true,
+            collapse_whitespace: true,
+        }
+    }
+
+    #[test]
+    fn each_step() {
+        let text = "Ca\u{0301}fe\u{0301} ﬁne  Ünïcödé\n\tİstanbul ²";
+        let nfc = Normaliser { form: Some(UnicodeForm::Nfc), ..Normaliser::none() };
+        assert_eq!(nfc.normalise(text), "Cáfé ﬁne  Ünïcödé\n\tİstanbul ²");
+        let nfkc = Normaliser { form: Some(UnicodeForm::Nfkc), ..Normaliser::none() };
+        assert_eq!(nfkc.normalise(text), "Cáfé fine  Ünïcödé\n\tİstanbul 2");
+        let strip = Normaliser { strip_accents: true, ..Normaliser::none() };
+        assert_eq!(strip.normalise(text), "Cafe ﬁne  Unicode\n\tIstanbul ²");
+        let lowercase = Normaliser { lowercase: true, ..Normaliser::none() };
+        assert_eq!(lowercase.normalise("ÜNÏCÖDÉ ΣΑΣ"), "ünïcödé σασ"); // a character at a time, so no final ς
+        let collapse = Normaliser { collapse_whitespace: true, ..Normaliser::none() };
+        assert_eq!(collapse.normalise(" a \n\t b "), " a b ");
+
+        assert_eq!(every_step(UnicodeForm::Nfkc).normalise(text), "cafe fine unicode istanbul 2");
+        assert_eq!(Normaliser::text8().normalise("The\nCat É"), "thecat é");
+        assert_eq!(strip.normalise("한국어"), "한국어");
+    }
+
+    #[test]
+    fn separable_cuts_dont_change_anything() {
+        // composing characters, Hangul jamo that compose with each other, whitespace and the odd compatibility character
+        let alphabet: Vec<char> = "ae \n\tAE\u{0301}\u{0308}\u{0323}\u{1100}\u{1161}\u{11A8}\u{AC00}\u{00B4}\u{00A0}\u{0B47}\u{0B3E}ﬁ²Σ".chars().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+
+        for normaliser in [every_step(UnicodeForm::Nfc), every_step(UnicodeForm::Nfkc), Normaliser { form: Some(UnicodeForm::Nfc), ..Normaliser::none() }, Normaliser::text8()] {
+            let mut cuts = 0;
+            for _ in 0..300 {
+                let text: String = (0..rng.gen_range(2..12)).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect();
+                let whole = normaliser.normalise(&text);
+                let (aligned, alignment) = normaliser.normalise_aligned(&text);
+                assert_eq!(aligned, whole);
+                for pair in alignment.windows(2) {
+                    assert_eq!(normaliser.normalise(&text[pair[0].1..pair[1].1]), &aligned[pair[0].0..pair[1].0]);
+                }
+                let chars: Vec<(usize, char)> = text.char_indices().collect();
+                for pair in chars.windows(2) {
+                    let ((_, before), (cut, after)) = (pair[0], pair[1]);
+                    if normaliser.separable(before, after) {
+                        cuts += 1;
+                        let parts = normaliser.normalise(&text[..cut]).into_owned() + &normaliser.normalise(&text[cut..]);
+                        assert_eq!(parts, whole, "cutting {:?} at {}", text, cut);
+                    }
+                }
+            }
+            assert!(cuts > 300);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+// GPT-2's split: contractions, then letters, numbers and anything else (each with an optional leading space), then whitespace
+pub const GPT2_PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+// cl100k_base (GPT-3.5 and 4): contractions in any case, numbers three digits at a time, line breaks kept together
+pub const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+// Every word with the whitespace after it - the same pieces read_word_counts() counts
+pub const WHITESPACE_PATTERN: &str = r"\S+\s*|\s+";
+
+// The regex crate has no lookahead, so `\s+(?!\S)|\s+` (whitespace, leaving the last space for the word after it)
+// is matched as plain whitespace in this group, and split() gives the last character back when a word follows
+const TRAILING_WHITESPACE: &str = r"\s+(?!\S)|\s+";
+const TRAILING_GROUP: &str = "trailing";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SplitPattern {
+    #[default]
+    None, // the whole input is one piece
+    Whitespace,
+    Gpt2,
+    Cl100k,
+    Custom(String), // any regex, with the same \s+(?!\S) allowance as the built in ones
+}
+
+impl SplitPattern {
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            SplitPattern::None => None,
+            SplitPattern::Whitespace => Some(WHITESPACE_PATTERN),
+            SplitPattern::Gpt2 => Some(GPT2_PATTERN),
+            SplitPattern::Cl100k => Some(CL100K_PATTERN),
+            SplitPattern::Custom(pattern) => Some(pattern),
+        }
+    }
+}
+
+// Cuts (normalised) text into pieces before BPE sees it, so no merge ever crosses from one piece into the next.
+// Used the same way by the Trainer and the Tokeniser, and saved with the model
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "PreTokeniserConfig", into = "PreTokeniserConfig")]
+pub struct PreTokeniser {
+    pattern: SplitPattern,
+    split_digits: bool, // every digit is a piece of its own, after the pattern has split
+    regex: Option<Regex>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PreTokeniserConfig {
+    pattern: SplitPattern,
+    #[serde(default)]
+    split_digits: bool,
+}
+
+impl TryFrom<PreTokeniserConfig> for PreTokeniser {
+    type Error = Error;
+
+    fn try_from(config: PreTokeniserConfig) -> Result<Self> {
+        Ok(PreTokeniser::new(config.pattern)?.with_split_digits(config.split_digits))
+    }
+}
+
+impl From<PreTokeniser> for PreTokeniserConfig {
+    fn from(pre_tokeniser: PreTokeniser) -> Self {
+        PreTokeniserConfig { pattern: pre_tokeniser.pattern, split_digits: pre_tokeniser.split_digits }
+    }
+}
+
+impl PartialEq for PreTokeniser {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.split_digits == other.split_digits
+    }
+}
+
+impl Eq for PreTokeniser {}
+
+impl PreTokeniser {
+    // Custom patterns that are one of the built in ones become that one
+    pub fn new(pattern: SplitPattern) -> Result<Self> {
+        let pattern = match pattern {
+            SplitPattern::Custom(custom) => match custom.as_str() {
+                WHITESPACE_PATTERN => SplitPattern::Whitespace,
+                GPT2_PATTERN => SplitPattern::Gpt2,
+                CL100K_PATTERN => SplitPattern::Cl100k,
+                _ => SplitPattern::Custom(custom),
+            },
+            pattern => pattern,
+        };
+
+        let regex = match pattern.source() {
+            Some(source) => {
+                let source = source.replace(TRAILING_WHITESPACE, &format!(r"(?P<{}>\s+)", TRAILING_GROUP));
+                Some(Regex::new(&source).map_err(Error::InvalidPattern)?)
+            },
+            None => None,
+        };
+        Ok(PreTokeniser { pattern, split_digits: false, regex })
+    }
+
+    pub fn none() -> Self {
+        PreTokeniser::default()
+    }
+
+    pub fn whitespace() -> Self {
+        Self::built_in(SplitPattern::Whitespace)
+    }
+
+    pub fn gpt2() -> Self {
+        Self::built_in(SplitPattern::Gpt2)
+    }
+
+    pub fn cl100k() -> Self {
+        Self::built_in(SplitPattern::Cl100k)
+    }
+
+    fn built_in(pattern: SplitPattern) -> Self {
+        Self::new(pattern).unwrap() // the built in patterns always compile, see the patterns_compile test
+    }
+
+    pub fn with_split_digits(mut self, split_digits: bool) -> Self {
+        self.split_digits = split_digits;
+        self
+    }
+
+    pub fn pattern(&self) -> &SplitPattern {
+        &self.pattern
+    }
+
+    pub fn splits_digits(&self) -> bool {
+        self.split_digits
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.regex.is_none() && !self.split_digits
+    }
+
+    // Byte ranges of the pieces, in order and together covering all of text.
+    // Text the pattern doesn't match at all still ends up in a piece of its own
+    pub fn split(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut pieces = Vec::new();
+        let mut covered = 0;
+
+        if let Some(regex) = &self.regex {
+            let mut search = 0;
+            while let Some(captures) = regex.captures_at(text, search) {
+                let Some(found) = captures.get(0) else {
+                    break;
+                };
+
+                if found.is_empty() { // patterns that can match nothing would never move on
+                    search = found.end() + text[found.end()..].chars().next().map_or(1, char::len_utf8);
+                    if search > text.len() {
+                        break;
+                    }
+                    continue;
+                }
+
+                let mut end = found.end();
+                if captures.name(TRAILING_GROUP).is_some() && end < text.len() && found.as_str().chars().nth(1).is_some() {
+                    end -= found.as_str().chars().last().map_or(0, char::len_utf8); // leave the last space for the word after it
+                }
+
+                if found.start() > covered {
+                    pieces.push((covered, found.start()));
+                }
+                pieces.push((found.start(), end));
+                covered = end;
+                search = end;
+            }
+        }
+
+        if covered < text.len() {
+            pieces.push((covered, text.len()));
+        }
+
+        if self.split_digits {
+            pieces = split_digits(text, pieces);
+        }
+        pieces
+    }
+}
+
+fn split_digits(text: &str, pieces: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    let mut split = Vec::with_capacity(pieces.len());
+    for (start, end) in pieces {
+        let mut piece_start = start;
+        for (offset, c) in text[start..end].char_indices() {
+            if c.is_numeric() {
+                let digit = start + offset;
+                if digit > piece_start {
+                    split.push((piece_start, digit));
+                }
+                split.push((digit, digit + c.len_utf8()));
+                piece_start = digit + c.len_utf8();
+            }
+        }
+        if piece_start < end {
+            split.push((piece_start, end));
+        }
+    }
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pieces<'a>(pre_tokeniser: &PreTokeniser, text: &'a str) -> Vec<&'a str> {
+        pre_tokeniser.split(text).into_iter().map(|(start, end)| &text[start..end]).collect()
+    }
+
+    #[test]
+    fn patterns_compile() {
+        for pattern in [SplitPattern::Whitespace, SplitPattern::Gpt2, SplitPattern::Cl100k] {
+            assert!(PreTokeniser::new(pattern).is_ok());
+        }
+        assert!(matches!(PreTokeniser::new(SplitPattern::Custom("(".to_string())), Err(Error::InvalidPattern(_))));
+        assert_eq!(PreTokeniser::new(SplitPattern::Custom(GPT2_PATTERN.to_string())).unwrap(), PreTokeniser::gpt2());
+    }
+
+    #[test]
+    fn splits_like_the_reference_patterns() {
+        // what tiktoken gives for the same text
+        let text = "Hello world, it's  12345 apples!\n\n  \tDon't";
+        assert_eq!(pieces(&PreTokeniser::gpt2(), text), ["Hello", " world", ",", " it", "'s", " ", " 12345", " apples", "!", "\n\n  ", "\t", "Don", "'t"]);
+        assert_eq!(pieces(&PreTokeniser::cl100k(), text), ["Hello", " world", ",", " it", "'s", " ", " ", "123", "45", " apples", "!\n\n", "  ", "\tDon", "'t"]);
+        assert_eq!(pieces(&PreTokeniser::whitespace(), text), ["Hello ", "world, ", "it's  ", "12345 ", "apples!\n\n  \t", "Don't"]);
+        assert_eq!(pieces(&PreTokeniser::whitespace().with_split_digits(true), "a12 b"), ["a", "1", "2", " ", "b"]);
+        assert_eq!(pieces(&PreTokeniser::none(), text), [text]);
+        assert_eq!(pieces(&PreTokeniser::new(SplitPattern::Custom("a".to_string())).unwrap(), "bab"), ["b", "a", "b"]);
+    }
+
+    #[test]
+    fn pieces_cover_the_text() {
+        for text in ["", " ", "   x", "x   ", "é🦀  \r\n 1 ", "a\u{0300}b"] {
+            for pre_tokeniser in [PreTokeniser::gpt2(), PreTokeniser::cl100k(), PreTokeniser::whitespace().with_split_digits(true)] {
+                assert_eq!(pieces(&pre_tokeniser, text).concat(), text);
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::byte_level;
+use crate::error::{Error, Result};
+use crate::tokeniser::{Merge, Tokeniser};
+
+// tiktoken's .tiktoken files: one "<base64 of the token's bytes> <rank>" line per token, where the rank is both the
+// token's id and its merge priority. There's no merge list - any two neighbouring pieces whose bytes join into a token
+// can merge, lowest ranked token first. Special tokens and the pre-tokenising regex aren't part of the file
+
+fn invalid(source: impl std::fmt::Display, line: usize, reason: &str) -> Error {
+    Error::MalformedRanks { source: source.to_string(), line: line + 1, reason: reason.to_string() }
+}
+
+impl Tokeniser {
+    // A byte level tokeniser with the file's ranks as ids
+    pub fn from_tiktoken<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::MissingVocab(path.to_path_buf()));
+        }
+        Self::read_tiktoken(BufReader::new(File::open(path)?), path.display())
+    }
+
+    pub fn from_tiktoken_str(ranks: &str) -> Result<Self> {
+        Self::read_tiktoken(ranks.as_bytes(), "tiktoken string")
+    }
+
+    fn read_tiktoken<R: BufRead>(reader: R, source: impl std::fmt::Display) -> Result<Self> {
+        let mut ranks: HashMap<Vec<u8>, usize> = HashMap::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let (token, rank) = line.split_once(' ').ok_or_else(|| invalid(&source, number, "expected a token and a rank"))?;
+            let token = STANDARD.decode(token).map_err(|_| invalid(&source, number, "the token isn't base64"))?;
+            let rank = rank.parse().map_err(|_| invalid(&source, number, "the rank isn't a number"))?;
+            if ranks.insert(token, rank).is_some() {
+                return Err(invalid(&source, number, "the token already has a rank"));
+            }
+        }
+
+        let mut by_rank: Vec<Option<&[u8]>> = vec![None; ranks.len()];
+        for (token, &rank) in &ranks {
+            match by_rank.get_mut(rank) {
+                Some(slot @ None) => *slot = Some(token),
+                Some(Some(_)) => return Err(Error::Unsupported(format!("two tokens with rank {}", rank))),
+                None => return Err(Error::Unsupported(format!("ranks with gaps, rank {} is past the {} tokens", rank, ranks.len()))),
+            }
+        }
+        let by_rank: Vec<&[u8]> = by_rank.into_iter().flatten().collect();
+
+        // every way of splitting a token into two others is a merge, ranked by the token it makes.
+        // Splits of the same token go in order of their halves' ranks
+        let mut splits: Vec<(usize, usize, usize)> = Vec::new();
+        for (rank, token) in by_rank.iter().enumerate() {
+            for split in 1..token.len() {
+                if let (Some(&left), Some(&right)) = (ranks.get(&token[..split]), ranks.get(&token[split..])) {
+                    splits.push((rank, left, right));
+                }
+            }
+        }
+        splits.sort_unstable();
+
+        let tokens: Vec<String> = by_rank.iter().map(|token| byte_level::encode(token)).collect();
+        let merges: Vec<Merge> = splits.into_iter()
+            .enumerate()
+            .map(|(index, (merged, left, right))| Merge {
+                left: tokens[left].clone(),
+                right: tokens[right].clone(),
+                merged: tokens[merged].clone(),
+                rank: index,
+                count: 0,
+            })
+            .collect();
+
+        Ok(Tokeniser::from_tokens(tokens, merges).with_byte_level(true))
+    }
+
+    // The model as a .tiktoken rank file. Ranks have to be merge priorities, so they're not this tokeniser's ids:
+    // the 256 single bytes come first, then every merged token in the order it was learned.
+    // Only works for models where every token is a byte or made by a merge, which is any byte level model and any
+    // character level one trained on ASCII
+    pub fn to_tiktoken(&self) -> Result<String> {
+        let token_bytes = |token: &str| if self.is_byte_level() { byte_level::decode(token) } else { token.as_bytes().to_vec() };
+
+        let mut ordered: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        let mut seen: HashSet<Vec<u8>> = ordered.iter().cloned().collect();
+        for merge in self.merges() {
+            let merged = token_bytes(&merge.merged);
+            if seen.insert(merged.clone()) { // two merges can make the same token, only the first one counts
+                ordered.push(merged);
+            }
+        }
+
+        for (index, token) in self.tokens().iter().enumerate() {
+            if !self.is_reserved(index) && !seen.contains(&token_bytes(token)) { // special tokens and the like aren't part of the file
+                return Err(Error::Unsupported(format!("{:?} is neither a single byte nor made by a merge", token)));
+            }
+        }
+
+        let mut output = String::new();
+        for (rank, token) in ordered.iter().enumerate() {
+            output.push_str(&format!("{} {}\n", STANDARD.encode(token), rank));
+        }
+        Ok(output)
+    }
+
+    pub fn save_tiktoken<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(self.to_tiktoken()?.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trainer::Trainer;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const FIXTURE: &str = include_str!("../fixtures/small.tiktoken");
+
+    // tiktoken's own byte_pair_encode: merge the leftmost of the lowest ranked pairs until nothing merges
+    fn reference_encode(ranks: &HashMap<Vec<u8>, usize>, input: &[u8]) -> Vec<usize> {
+        let mut parts: Vec<Vec<u8>> = input.iter().map(|&byte| vec![byte]).collect();
+        loop {
+            let best = parts.windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| ranks.get(&[pair[0].as_slice(), pair[1].as_slice()].concat()).map(|&rank| (rank, i)))
+                .min();
+            let Some((_, i)) = best else {
+                break;
+            };
+            let right = parts.remove(i + 1);
+            parts[i].extend(right);
+        }
+        parts.iter().map(|part| ranks[part]).collect()
+    }
+
+    fn random_text(rng: &mut StdRng) -> String {
+        let alphabet: Vec<char> = "the cat and the hat in the thing, é🦀\n".chars().collect();
+        (0..rng.gen_range(0..80)).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect()
+    }
+
+    #[test]
+    fn encodes_like_tiktoken() {
+        let tokeniser = Tokeniser::from_tiktoken_str(FIXTURE).unwrap();
+        let ranks: HashMap<Vec<u8>, usize> = FIXTURE.lines()
+            .map(|line| line.split_once(' ').unwrap())
+            .map(|(token, rank)| (STANDARD.decode(token).unwrap(), rank.parse().unwrap()))
+            .collect();
+        assert_eq!(tokeniser.vocab_size(), ranks.len());
+        assert_eq!(tokeniser.encode(" the cat").unwrap(), vec![ranks[&b" the"[..]], ranks[&b" cat"[..]]]);
+
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..300 {
+            let input = random_text(&mut rng);
+            let tokens = tokeniser.encode(&input).unwrap();
+            assert_eq!(tokens, reference_encode(&ranks, input.as_bytes()));
+            assert_eq!(tokeniser.reconstruct(&tokens).unwrap(), input);
+        }
+
+        assert!(matches!(Tokeniser::from_tiktoken_str("dGhl 0\nnot base64! 1"), Err(Error::MalformedRanks { line: 2, .. })));
+        assert!(matches!(Tokeniser::from_tiktoken_str("dGhl 0\nIGNhdA== 1\ndGhl 2"), Err(Error::MalformedRanks { line: 3, .. })));
+        assert!(matches!(Tokeniser::from_tiktoken_str("dGhl 5"), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn exports_trained_models() {
+        let training = "the cat and the hat sat on the mat, then the cat and the rat ate the hat";
+        let mut trainer = Trainer::new(byte_level::corpus(training.as_bytes()), HashMap::new()).with_alphabet(&byte_level::alphabet());
+        trainer.train(300).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+        let trained = Tokeniser::from_vocab(&vocab, merges).with_byte_level(true);
+
+        let exported = trained.to_tiktoken().unwrap();
+        let reimported = Tokeniser::from_tiktoken_str(&exported).unwrap();
+        assert_eq!(reimported.vocab_size(), trained.vocab_size());
+
+        // the ids are ranks now, but every input still splits into the same tokens
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..300 {
+            let input = random_text(&mut rng);
+            let pieces = |tokeniser: &Tokeniser| -> Vec<String> {
+                tokeniser.encode(&input).unwrap().iter().map(|&id| tokeniser.tokens()[id].clone()).collect()
+            };
+            assert_eq!(pieces(&reimported), pieces(&trained));
+        }
+
+        let unicode = Tokeniser::from_json(r#"{"é": 1, "a": 2}"#).unwrap();
+        assert!(matches!(unicode.to_tiktoken(), Err(Error::Unsupported(_))));
+    }
+}
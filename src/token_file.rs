@@ -0,0 +1,212 @@
+use std::io::{Read, Write};
+
+use crate::error::{Error, Result};
+use crate::tokeniser::Tokeniser;
+
+// Encoded token ids on disk. A 16 byte header followed by every id as a little endian u16 or u32:
+//
+//   bytes 0..4    MAGIC
+//   byte  4       VERSION
+//   byte  5       id width in bytes (2 or 4)
+//   bytes 6..8    zero
+//   bytes 8..16   Tokeniser::vocab_hash() of the vocab the ids came from, little endian
+//
+// The header is a multiple of 4 bytes so the ids stay aligned when the file is memory mapped as a [u16] or [u32]
+pub const MAGIC: [u8; 4] = *b"BPE\0";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdWidth {
+    U16,
+    U32,
+}
+
+impl IdWidth {
+    // The smallest width that fits every id in the vocab
+    pub fn for_vocab_size(size: usize) -> Self {
+        if size <= u16::MAX as usize + 1 { IdWidth::U16 } else { IdWidth::U32 }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            IdWidth::U16 => 2,
+            IdWidth::U32 => 4,
+        }
+    }
+
+    fn from_bytes(bytes: u8) -> Option<Self> {
+        match bytes {
+            2 => Some(IdWidth::U16),
+            4 => Some(IdWidth::U32),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub width: IdWidth,
+    pub vocab_hash: u64,
+}
+
+impl Header {
+    pub fn for_tokeniser(tokeniser: &Tokeniser) -> Self {
+        Header {
+            width: IdWidth::for_vocab_size(tokeniser.vocab_size()),
+            vocab_hash: tokeniser.vocab_hash(),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.width.bytes() as u8;
+        bytes[8..].copy_from_slice(&self.vocab_hash.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Result<Self> {
+        if bytes[..4] != MAGIC {
+            return Err(Error::MalformedTokens("not a token file".to_string()));
+        }
+        if bytes[4] != VERSION {
+            return Err(Error::MalformedTokens(format!("unsupported version {}", bytes[4])));
+        }
+        let width = IdWidth::from_bytes(bytes[5]).ok_or_else(|| Error::MalformedTokens(format!("unsupported id width {}", bytes[5])))?;
+
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&bytes[8..]);
+        Ok(Header { width, vocab_hash: u64::from_le_bytes(hash) })
+    }
+
+    // Errors if the ids were written with a different vocab to this one, since they'd decode to the wrong tokens
+    pub fn check(&self, tokeniser: &Tokeniser) -> Result<()> {
+        let vocab_hash = tokeniser.vocab_hash();
+        if self.vocab_hash != vocab_hash {
+            return Err(Error::VocabMismatch { expected: self.vocab_hash, found: vocab_hash });
+        }
+        Ok(())
+    }
+}
+
+// Writes the header straight away, then ids as they come. Wrap the writer in a BufWriter, ids are written one at a time
+pub struct TokenWriter<W: Write> {
+    writer: W,
+    header: Header,
+    written: usize,
+}
+
+impl<W: Write> TokenWriter<W> {
+    pub fn new(mut writer: W, header: Header) -> Result<Self> {
+        writer.write_all(&header.to_bytes())?;
+        Ok(TokenWriter { writer, header, written: 0 })
+    }
+
+    pub fn write(&mut self, ids: &[usize]) -> Result<()> {
+        for &id in ids {
+            match self.header.width {
+                IdWidth::U16 => {
+                    let id = u16::try_from(id).map_err(|_| Error::MalformedTokens(format!("id {} doesn't fit in a u16", id)))?;
+                    self.writer.write_all(&id.to_le_bytes())?;
+                },
+                IdWidth::U32 => {
+                    let id = u32::try_from(id).map_err(|_| Error::MalformedTokens(format!("id {} doesn't fit in a u32", id)))?;
+                    self.writer.write_all(&id.to_le_bytes())?;
+                },
+            }
+        }
+        self.written += ids.len();
+        Ok(())
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    // Flushes and hands the writer back
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Reads the header up front, then the ids one at a time as an iterator
+pub struct TokenReader<R: Read> {
+    reader: R,
+    header: Header,
+}
+
+impl<R: Read> TokenReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header).map_err(|_| Error::MalformedTokens("too short for the header".to_string()))?;
+        Ok(TokenReader { header: Header::from_bytes(&header)?, reader })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    pub fn read_to_end(self) -> Result<Vec<usize>> {
+        self.collect()
+    }
+}
+
+impl<R: Read> Iterator for TokenReader<R> {
+    type Item = Result<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut id = [0; 4];
+        let id = &mut id[..self.header.width.bytes()];
+
+        // read_exact can't tell a clean end from a cut off id, so read the first byte on its own
+        match self.reader.read(&mut id[..1]) {
+            Ok(0) => return None,
+            Ok(_) => {},
+            Err(e) => return Some(Err(e.into())),
+        }
+        if self.reader.read_exact(&mut id[1..]).is_err() {
+            return Some(Err(Error::MalformedTokens("the file ends part way through an id".to_string())));
+        }
+
+        Some(Ok(match self.header.width {
+            IdWidth::U16 => u16::from_le_bytes([id[0], id[1]]) as usize,
+            IdWidth::U32 => u32::from_le_bytes([id[0], id[1], id[2], id[3]]) as usize,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_round_trip() {
+        let tokeniser = Tokeniser::bundled(crate::tokeniser::DEFAULT_BUNDLED).unwrap();
+        let text = "the quick brown fox jumped over the lazy dog";
+        let ids = tokeniser.encode(text).unwrap();
+
+        let header = Header::for_tokeniser(&tokeniser);
+        assert_eq!(header.width, IdWidth::U16);
+        let mut writer = TokenWriter::new(Vec::new(), header).unwrap();
+        writer.write(&ids).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + ids.len() * 2);
+
+        let reader = TokenReader::new(bytes.as_slice()).unwrap();
+        reader.header().check(&tokeniser).unwrap();
+        assert_eq!(reader.read_to_end().unwrap(), ids);
+
+        let other = Tokeniser::from_json(r#"{"a": 1}"#).unwrap();
+        assert!(matches!(TokenReader::new(bytes.as_slice()).unwrap().header().check(&other), Err(Error::VocabMismatch { .. })));
+        assert!(matches!(TokenReader::new(&bytes[..HEADER_LEN + 3]).unwrap().read_to_end(), Err(Error::MalformedTokens(_))));
+        assert!(matches!(TokenReader::new("1 2 3".as_bytes()), Err(Error::MalformedTokens(_))));
+
+        let mut wide = TokenWriter::new(Vec::new(), Header { width: IdWidth::U32, vocab_hash: 7 }).unwrap();
+        wide.write(&[70_000, 0]).unwrap();
+        let wide = wide.finish().unwrap();
+        assert_eq!(TokenReader::new(wide.as_slice()).unwrap().read_to_end().unwrap(), vec![70_000, 0]);
+    }
+}
@@ -1,161 +1,595 @@
-use std::{collections::HashMap, fs, io, path::Path};
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}, fmt, fs::{self, File}, io::{BufRead, BufReader, Read, Write}, ops::Range, path::{Path, PathBuf}};
 use rayon::prelude::*;
-use serde_json; // Ensure serde_json is available for JSON processing
-use colored::{Colorize, CustomColor};
-use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use daachorse::{CharwiseDoubleArrayAhoCorasickBuilder, MatchKind, CharwiseDoubleArrayAhoCorasick};
 
-pub type CharInfo = (char, Option<(usize, usize)>); // Might need to make this CharInfo = (char, Option<(usize, usize))
+use crate::byte_level;
+use crate::encoding::Encoding;
+use crate::error::{Error, Result};
+use crate::model_input::{prepare_batch, ModelInput, Padding, Truncation};
+use crate::normaliser::Normaliser;
+use crate::pre_tokeniser::PreTokeniser;
+use crate::token_file::{Header, TokenWriter};
+use crate::vocab::{assign_ids, VocabFile};
+
+#[cfg(any(test, feature = "bench"))]
+type CharInfo = (char, Option<(usize, usize)>); // a character and the (token, occurrence) covering it, in the sliding window encoder
+
+type Piece = (Option<usize>, usize, usize); // (token index or None if the character isn't in the vocab, byte start, byte end)
+type Located = (usize, usize, usize); // (token index, byte start, byte end)
+
+// A run of characters in apply_merges(), in a linked list so merging two only touches them and their neighbours
+struct Symbol {
+    id: u32, // token index, or one of the two below
+    start: usize,
+    end: usize,
+    prev: usize,
+    next: usize,
+}
+
+const UNKNOWN_SYMBOL: u32 = u32::MAX; // a character that isn't in the vocab, which no merge ever uses
+const MERGED_SYMBOL: u32 = u32::MAX - 1; // merged into the symbol before it
+const NO_SYMBOL: usize = usize::MAX; // end of the list
+
+// The vocabularies from output/, built into the library so they load wherever it's installed (see Tokeniser::bundled())
+const BUNDLED: [(&str, &str); 4] = [
+    ("1.5M_words-10k_tokens", include_str!("../output/1.5M_words-10k_tokens.json")),
+    ("1.5M_words-30k_tokens", include_str!("../output/1.5M_words-30k_tokens.json")),
+    ("1M_words-55k_tokens", include_str!("../output/1M_words-55k_tokens.json")),
+    ("2M_words-17k_tokens", include_str!("../output/2M_words-17k_tokens.json")),
+];
+pub const DEFAULT_BUNDLED: &str = "1.5M_words-10k_tokens";
+
+pub const UNKNOWN_TOKEN: &str = "<unk>";
+pub const END_OF_TEXT: &str = "<|endoftext|>";
+pub const PAD_TOKEN: &str = "<|pad|>";
+
+const STREAM_CHUNK: usize = 1 << 16; // bytes encode_stream() reads before it looks for somewhere to cut
+const STREAM_LIMIT: usize = 1 << 20; // most input encode_stream() holds waiting for a safe cut before it forces one
+const RESOLVE_BATCH: usize = 1 << 10; // matches find_tokens() collects before it tries to settle the ones behind it
+const RESOLVE_LIMIT: usize = 1 << 16; // most matches find_tokens() holds before it settles them whether or not it's safe
+const FORCED_LOOKBACK: usize = 1 << 10; // how far back from the end a forced cut looks for whitespace
+
+// What to do with characters the vocab can't encode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownPolicy {
+    #[default]
+    Skip, // drop them
+    Token, // emit the reserved <unk> token for each one
+    Bytes, // emit one <0xXX> token per UTF-8 byte, so they still reconstruct
+    Error, // refuse to encode and list where they are
+}
+
+// Returned under UnknownPolicy::Error. Spans are byte ranges into the normalised input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnencodableInput {
+    pub spans: Vec<(usize, usize, String)>,
+}
+
+impl fmt::Display for UnencodableInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "input contains text the vocab can't encode:")?;
+        for (start, end, text) in &self.spans {
+            write!(f, " {:?} at {}..{}", text, start, end)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnencodableInput {}
+
+impl UnencodableInput {
+    fn shifted(mut self, offset: usize) -> Self {
+        for span in self.spans.iter_mut() {
+            span.0 += offset;
+            span.1 += offset;
+        }
+        self
+    }
+}
+
+// Where a token came from in the input as it was given, before normalisation, in bytes and in chars.
+// A token that's only part of a character (a byte level token with half an emoji, say) gets the whole character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenOffsets {
+    pub id: usize,
+    pub bytes: Range<usize>,
+    pub chars: Range<usize>,
+}
+
+// A single learned merge rule (left + right -> merged), as recorded by the Trainer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Merge {
+    pub left: String,
+    pub right: String,
+    pub merged: String,
+    pub rank: usize, // position in the merge list, 0 is the first merge made
+    pub count: i32, // how often the pair occurred when it was merged
+}
+
+// How input is turned into pieces before BPE, which training and encoding have to agree on.
+// Saved next to the vocabulary as <name>.config.json (see config_path_for())
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelConfig {
+    #[serde(default)]
+    pub normaliser: Normaliser,
+    #[serde(default)]
+    pub pre_tokeniser: PreTokeniser,
+    #[serde(default)]
+    pub byte_level: bool,
+}
+
+impl ModelConfig {
+    // Normalises the text, splits it, and maps each piece to the symbols the vocab is over
+    pub fn pieces(&self, text: &str) -> Vec<String> {
+        let text = self.normaliser.normalise(text);
+        self.pre_tokeniser.split(&text).into_iter().map(|(start, end)| self.symbols(&text[start..end])).collect()
+    }
+
+    // Already normalised text as the vocab's symbols
+    pub fn symbols(&self, text: &str) -> String {
+        if self.byte_level { byte_level::encode(text.as_bytes()) } else { text.to_string() }
+    }
+}
 
 #[derive(Default)]
 pub struct Tokeniser {
     vocab: Vec<String>, // The list of tokens
-    decoded: Option<Vec<String>>, // the final output
     vocab_map: HashMap<String, usize>, // mapping each string to its index
-    colour_map: HashMap<usize, (u8, u8, u8)>, // maps each token to a unique colour (thats light enough to read text against)
+    merges: Vec<Merge>, // the learned merges, ordered by rank
+    merge_ranks: HashMap<(u32, u32), (u32, u32)>, // (left id, right id) -> (position in merges, merged id), lower merges first
+    matcher: Option<CharwiseDoubleArrayAhoCorasick<usize>>, // built once from the vocab, None if the vocab is empty
+    longest_token: usize, // in bytes, so how far before where a match ends it can start
+    byte_level: bool, // tokens are over the 256 byte symbols from byte_level rather than characters
+    normaliser: Normaliser, // applied to input before encoding, nothing by default
+    pre_tokeniser: PreTokeniser, // splits the normalised input into pieces that are encoded separately, none by default
+    unknown_policy: UnknownPolicy,
+    byte_fallback: Option<usize>, // index of <0x00> when UnknownPolicy::Bytes added the byte tokens, the rest follow in order
+    special_tokens: HashMap<String, usize>, // registered special tokens -> index, these are never split
+    special_matcher: Option<CharwiseDoubleArrayAhoCorasick<usize>>, // finds special tokens in raw input
+    allow_special: bool, // whether special tokens written in the input are recognised, off so user text can't inject them
 }
 
 
 impl Tokeniser {
-    pub fn new() -> Result<Self, io::Error> {
-        let initial_vocab_path = "output/vocabulary.json";
+    // Loads output/vocabulary.json if one has been trained, otherwise the default bundled vocabulary
+    pub fn new() -> Result<Self> {
+        let trained_path = Path::new("output/vocabulary.json");
+
+        if trained_path.exists() && config_path_for(trained_path).exists() {
+            Self::from_file(trained_path)
+        } else if trained_path.exists() {
+            Ok(Self::from_file(trained_path)?.with_normaliser(Normaliser::text8())) // saved before configs were, by training on text8
+        } else {
+            Self::bundled(DEFAULT_BUNDLED)
+        }
+    }
+
+    // One of the vocabularies shipped in output/, by file name without the .json (see bundled_vocabularies())
+    pub fn bundled(name: &str) -> Result<Self> {
+        let Some(&(_, json)) = BUNDLED.iter().find(|&&(bundled, _)| bundled == name) else {
+            return Err(Error::MissingVocab(PathBuf::from(format!("{}.json", name))));
+        };
+        Ok(Self::parse_json(json, name)?.with_normaliser(Normaliser::text8())) // they were all trained on text8
+    }
+
+    // A vocabulary by name from a directory of them laid out like output/ (see vocabularies_in()). Merges and config
+    // next to it are picked up the same as from_file(), and without a config it's taken to be trained on text8
+    pub fn from_dir<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self> {
+        let path = dir.as_ref().join(format!("{}.json", name));
+        if config_path_for(&path).exists() {
+            Self::from_file(path)
+        } else {
+            Ok(Self::from_file(path)?.with_normaliser(Normaliser::text8()))
+        }
+    }
+
+    // Reads a vocabulary file (see vocab::save_vocabulary). Merges are picked up from <name>.merges.json next to it if that exists,
+    // and the normaliser, pre-tokeniser and byte level setting from <name>.config.json
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::MissingVocab(path.to_path_buf()));
+        }
 
-        if !Path::new(initial_vocab_path).exists() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Vocab file does not exist"));
+        let file: VocabFile = serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map_err(|e| Error::malformed(path.display(), e))?;
+        let tokens = file.into_tokens(path.display())?;
+
+        // merges are optional - older vocabularies were saved without them
+        let merges_path = merges_path_for(path);
+        let merges: Vec<Merge> = if merges_path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&merges_path)?))
+                .map_err(|e| Error::malformed(merges_path.display(), e))?
+        } else {
+            Vec::new()
+        };
+
+        let config_path = config_path_for(path);
+        let tokeniser = Self::from_tokens(tokens, merges);
+        if !config_path.exists() {
+            return Ok(tokeniser);
         }
+        let config: ModelConfig = serde_json::from_reader(BufReader::new(File::open(&config_path)?))
+            .map_err(|e| Error::malformed(config_path.display(), e))?;
+        Ok(tokeniser.with_config(config))
+    }
+
+    // A vocabulary from any reader, without merges
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let file: VocabFile = serde_json::from_reader(reader).map_err(|e| Error::malformed("reader", e))?;
+        Ok(Self::from_tokens(file.into_tokens("reader")?, Vec::new()))
+    }
+
+    // A vocabulary already in memory as JSON, without merges
+    pub fn from_json(json: &str) -> Result<Self> {
+        Self::parse_json(json, "JSON string")
+    }
 
-        let json = fs::read_to_string(initial_vocab_path)?;
-        let map: HashMap<String, i32> = serde_json::from_str(&json)?;
+    fn parse_json(json: &str, source: &str) -> Result<Self> {
+        let file: VocabFile = serde_json::from_str(json).map_err(|e| Error::malformed(source, e))?;
+        Ok(Self::from_tokens(file.into_tokens(source)?, Vec::new()))
+    }
 
-        // Collect only the keys (tokens) from the map and sort them by length
-        let mut tokens: Vec<String> = map.keys().cloned().collect();
-        tokens.sort_by(|a, b| b.len().cmp(&a.len())); // sort them in decreasing order by length
-        println!("Token Amount: {}", tokens.len());
+    // A vocabulary straight from the Trainer, with ids given out the same way save_vocabulary() does
+    pub fn from_vocab(map: &HashMap<String, i32>, merges: Vec<Merge>) -> Self {
+        Self::from_tokens(assign_ids(map), merges)
+    }
 
+    // Tokens already in id order, so token i gets id i. For vocabularies that come with their own ids (eg Hugging Face)
+    pub fn from_tokens(tokens: Vec<String>, mut merges: Vec<Merge>) -> Self {
         let vocab_map: HashMap<String, usize> = tokens
             .par_iter()
             .enumerate()
             .map(|(index, token)| (token.to_owned(), index))
             .collect();
 
-        let mut rng = thread_rng(); // random colours for each token
-        let mut colour_map: HashMap<usize, (u8, u8, u8)> = HashMap::new();
-
-        for i in 0..tokens.len() {
-            let r: u8 = rng.gen_range(0..=255);
-            let g: u8 = rng.gen_range(0..=255);
-            let b: u8 = rng.gen_range(0..=255);
-
-            let colour = (r, g, b);
+        // every token as a pattern, valued by its index in the vocab
+        let matcher = token_matcher(tokens.iter().enumerate().map(|(index, token)| (token.as_str(), index)), MatchKind::Standard);
 
-            colour_map.insert(i, colour);
+        merges.sort_by_key(|merge| merge.rank);
+        let id = |token: &str| vocab_map.get(token).map(|&index| index as u32);
+        let mut merge_ranks: HashMap<(u32, u32), (u32, u32)> = HashMap::with_capacity(merges.len());
+        for (rank, merge) in merges.iter().enumerate() {
+            // a merge that makes or uses something outside the vocab could never give a token, so it's left out
+            if let (Some(left), Some(right), Some(merged)) = (id(&merge.left), id(&merge.right), id(&merge.merged)) {
+                merge_ranks.entry((left, right)).or_insert((rank as u32, merged));
+            }
         }
 
-        Ok(Tokeniser {
+        Tokeniser {
+            longest_token: tokens.iter().map(String::len).max().unwrap_or(0),
             vocab: tokens, 
-            decoded: None,
             vocab_map,
-            colour_map,
-        })
+            merges,
+            merge_ranks,
+            matcher,
+            byte_level: false,
+            normaliser: Normaliser::none(),
+            pre_tokeniser: PreTokeniser::none(),
+            unknown_policy: UnknownPolicy::Skip,
+            byte_fallback: None,
+            special_tokens: HashMap::new(),
+            special_matcher: None,
+            allow_special: false,
+        }
     }
 
-    pub fn tokenise(&mut self, input: &String) -> Vec<String> {
-        let input = input.to_ascii_lowercase(); // only trained on lowercase letters
+    // Registers special tokens (eg END_OF_TEXT, PAD_TOKEN). Each gets a fixed index after the vocab, in the order given
+    pub fn with_special_tokens(mut self, tokens: &[&str]) -> Self {
+        let already_in_vocab = tokens.iter().any(|token| self.vocab_map.contains_key(*token));
+        for token in tokens {
+            let index = self.reserve_token(token);
+            self.special_tokens.insert(token.to_string(), index);
+        }
 
-        if input.is_empty() { // Default cases
-            return Vec::new();
-        } else if input.len() == 1 {
-            return vec![input.to_string()];
+        self.special_matcher = token_matcher(self.special_tokens.iter().map(|(token, &index)| (token.as_str(), index)), MatchKind::LeftmostLongest);
+        if already_in_vocab {
+            self.rebuild_matcher();
         }
+        self
+    }
 
-        let input_size = input.len();
+    // When allowed, special tokens written out in the input become their token. Otherwise they're encoded like any other text
+    pub fn with_allow_special(mut self, allow: bool) -> Self {
+        self.allow_special = allow;
+        self
+    }
 
-        let mut position: Vec<CharInfo> = input.chars()
-            .filter(|&c| c != '\n')
-            .map(|c| (c, None)) 
-            .collect();
+    pub fn special_token_id(&self, token: &str) -> Option<usize> {
+        self.special_tokens.get(token).copied()
+    }
+
+    pub fn token_id(&self, token: &str) -> Option<usize> {
+        self.vocab_map.get(token).copied()
+    }
 
+    pub fn vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
 
-        // iterate through every token
-        // slide a window of said token over position vector -- ie if token is 3 characters long look at the 3 consecutive indices
-        // if theres a match add to an index vector
-        // then iterate through the index vector and check that theres space for this string (ie another token isnt already in position)
-        //    if true:  copy across token char by char into the position vector and update 
-        //    if false: continue to the next occurence
-        // continue through all tokens
+    // Every token, by index
+    pub fn tokens(&self) -> &[String] {
+        &self.vocab
+    }
 
-        // this works because the tokens are sorted - the larger tokens filters as much as possible and all remaining tokens can be done by character
-        for (token_index, token) in self.vocab.iter().enumerate() { // every character in the string is guarenteed to be covered by one of the tokens
-            let window_size = token.len();
-            //let mut count = 0; // this would keep track of the occurences of successive tokens
+    pub fn merges(&self) -> &[Merge] {
+        &self.merges
+    }
 
-            if window_size > input_size { // if a tokens length is greater than the input its not made up of the token
-                continue; // for the time being I cant filter the vocab list because i need the specific index
-            }
+    pub fn is_byte_level(&self) -> bool {
+        self.byte_level
+    }
 
-            if position.iter().all(|(_, c)| c.is_some()) { // end the token checking if all characters are accounted for
-                break;
+    pub fn special_tokens(&self) -> &HashMap<String, usize> {
+        &self.special_tokens
+    }
+
+    // FNV-1a over every token in id order, so it changes whenever any id would decode differently.
+    // Written into token files to catch ids being decoded with the wrong vocab
+    pub fn vocab_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for token in &self.vocab {
+            for &byte in token.as_bytes().iter().chain(&[0xff]) { // 0xff never appears in UTF-8, so it separates tokens
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
             }
-            let mut count = 0;
+        }
+        hash
+    }
 
-            for i in 0..=position.len() - window_size { // create the window
-                let window = &position[i..i + window_size]; // slide window across
-                if window.iter().zip(token.chars()).all(|(&(c, b), t)| c == t && b.is_none()) { // check if the token matches
-                    for index in i..i + window_size {
-                        position[index].1 = Some((token_index, count)); // Mark with the token index
-                    }
-                    count += 1;
+    fn is_special(&self, index: usize) -> bool {
+        self.special_tokens.values().any(|&special| special == index)
+    }
+
+    // Cuts the raw input around any special tokens (if they're allowed) and encodes the text between them.
+    // This happens before normalisation so a special token is only ever matched exactly as registered
+    fn split_special<F>(&self, text: &str, encode_segment: F) -> std::result::Result<Vec<usize>, UnencodableInput>
+    where
+        F: Fn(&str) -> std::result::Result<Vec<usize>, UnencodableInput>,
+    {
+        let mut tokens = Vec::new();
+        let mut covered = 0;
+        for (index, start, end) in self.find_special(text) {
+            tokens.extend(encode_segment(&text[covered..start]).map_err(|e| e.shifted(covered))?);
+            tokens.push(index);
+            covered = end;
+        }
+        tokens.extend(encode_segment(&text[covered..]).map_err(|e| e.shifted(covered))?);
+        Ok(tokens)
+    }
+
+    // The special tokens in the raw input, none unless they're allowed
+    fn find_special(&self, text: &str) -> Vec<Located> {
+        match &self.special_matcher {
+            Some(matcher) if self.allow_special => matcher.leftmost_find_iter(text).map(|m| (m.value(), m.start(), m.end())).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Token and Bytes reserve their tokens at the end of the vocab. They aren't added to the matcher
+    // so they only ever come from the policy, never from input text that happens to spell them out
+    pub fn with_unknown_policy(mut self, policy: UnknownPolicy) -> Self {
+        let already_in_vocab = self.vocab_map.contains_key(UNKNOWN_TOKEN) || self.vocab_map.contains_key("<0x00>");
+        match policy {
+            UnknownPolicy::Token => {
+                self.reserve_token(UNKNOWN_TOKEN);
+            },
+            UnknownPolicy::Bytes => {
+                let first = self.reserve_token("<0x00>");
+                for byte in 1..=255u8 {
+                    self.reserve_token(&format!("<0x{:02X}>", byte));
                 }
-            } // window.iter().map(|(c, _)| c.to_owned()).collect::<Vec<_>>().join("") == *token && window.iter().all(|(_, b)| *b == None) - 124s
-        }     // window.iter().map(|&(c, _)| c).eq(token.chars()) && window.iter().all(|(_, b)| *b == None) - 800ms (current 550-600ms)
+                self.byte_fallback = Some(first);
+            },
+            UnknownPolicy::Skip | UnknownPolicy::Error => {},
+        }
+        self.unknown_policy = policy;
+        if already_in_vocab {
+            self.rebuild_matcher();
+        }
+        self
+    }
 
-        let mut count = 0; // Debugging and Error information
-        let mut missing_vec = Vec::new();
-        
-        for item in position.iter() {
-            let (token, value) = item;
-            if value.is_none() { // character not covered by the token set (currently mainly punctuation)
-                count += 1;
-                missing_vec.push(token.to_owned())
-            }
+    pub fn unknown_policy(&self) -> UnknownPolicy {
+        self.unknown_policy
+    }
+
+    // Tokens that only come from the unknown policy or special token matching, never from matching ordinary text
+    pub(crate) fn is_reserved(&self, index: usize) -> bool {
+        let unknown = self.unknown_policy == UnknownPolicy::Token && self.vocab[index] == UNKNOWN_TOKEN;
+        let byte = self.byte_fallback.is_some_and(|first| (first..first + 256).contains(&index));
+        unknown || byte || self.is_special(index)
+    }
+
+    // Only needed when a vocab that was loaded already had the reserved tokens in it (eg from a Hugging Face file),
+    // since reserving a new token appends it after the matcher was built
+    fn rebuild_matcher(&mut self) {
+        let tokens = self.vocab.iter().enumerate().filter(|&(index, _)| !self.is_reserved(index));
+        self.matcher = token_matcher(tokens.map(|(index, token)| (token.as_str(), index)), MatchKind::Standard);
+    }
+
+    fn reserve_token(&mut self, token: &str) -> usize {
+        if let Some(&index) = self.vocab_map.get(token) {
+            return index;
         }
+        let index = self.vocab.len();
+        self.vocab.push(token.to_string());
+        self.vocab_map.insert(token.to_string(), index);
+        index
+    }
 
-        if !missing_vec.is_empty() { // unknown or unencoded tokens
-            println!("{}", count);
-            println!("{:?}", missing_vec);
+    pub fn with_normaliser(mut self, normaliser: Normaliser) -> Self {
+        self.normaliser = normaliser;
+        self
+    }
+
+    pub fn normaliser(&self) -> Normaliser {
+        self.normaliser
+    }
+
+    // Turns off all normalisation so reconstruct() gives back exactly what was encoded. A vocab that isn't byte level
+    // also falls back to byte tokens (UnknownPolicy::Bytes) for anything it doesn't have, so nothing is dropped either
+    pub fn with_lossless(self) -> Self {
+        let tokeniser = self.with_normaliser(Normaliser::none());
+        if tokeniser.byte_level {
+            tokeniser
+        } else {
+            tokeniser.with_unknown_policy(UnknownPolicy::Bytes)
         }
+    }
+
+    // True when every input round trips exactly: nothing is normalised away and every byte has a token
+    pub fn is_lossless(&self) -> bool {
+        self.normaliser.is_identity() && ((self.byte_level && self.has_byte_symbols()) || self.unknown_policy == UnknownPolicy::Bytes)
+    }
 
-        let output =  self.recreate_string(&position);
+    // Whether all 256 of byte_level's symbols are tokens, which a byte level vocab needs to encode any input
+    pub fn has_byte_symbols(&self) -> bool {
+        (0..=255u8).all(|byte| self.vocab_map.contains_key(byte_level::symbol(byte).encode_utf8(&mut [0; 4]) as &str))
+    }
 
-        self.decoded = Some(output.clone()); // For now
-        output
+    // For vocabularies trained on byte_level::corpus - input is mapped to byte symbols so any input is encodable
+    pub fn with_byte_level(mut self, byte_level: bool) -> Self {
+        self.byte_level = byte_level;
+        self
     }
 
+    pub fn with_pre_tokeniser(mut self, pre_tokeniser: PreTokeniser) -> Self {
+        self.pre_tokeniser = pre_tokeniser;
+        self
+    }
 
-    // CharInfo = (char, Option<(usize, usize)>)
-    fn recreate_string(&self, position_vector: &[CharInfo]) -> Vec<String> {
-        let mut result = Vec::new();
-        let mut last_token: Option<(usize, usize)> = None;
+    pub fn pre_tokeniser(&self) -> &PreTokeniser {
+        &self.pre_tokeniser
+    }
 
-        for (_, e) in position_vector { // condense each (char, index) map to just the respective token index and count
-            if let Some(num) = e {
-                if Some(num) != last_token.as_ref() {
-                    last_token = Some(*num);
-                    result.push(num);
+    pub fn config(&self) -> ModelConfig {
+        ModelConfig {
+            normaliser: self.normaliser,
+            pre_tokeniser: self.pre_tokeniser.clone(),
+            byte_level: self.byte_level,
+        }
+    }
+
+    pub fn with_config(self, config: ModelConfig) -> Self {
+        self.with_normaliser(config.normaliser)
+            .with_pre_tokeniser(config.pre_tokeniser)
+            .with_byte_level(config.byte_level)
+    }
+
+    // Turns raw input into the symbols the vocab was trained on
+    fn prepare(&self, text: &str) -> String {
+        self.prepare_normalised(&self.normaliser.normalise(text))
+    }
+
+    fn prepare_normalised(&self, text: &str) -> String {
+        if self.byte_level {
+            byte_level::encode(text.as_bytes())
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn tokenise(&self, input: &str) -> Result<Vec<String>> {
+        let tokens = self.get_tokens_from_text(input)?;
+        Ok(tokens.iter().map(|&index| self.vocab[index].clone()).collect())
+    }
+
+    // Runs the matcher over the input, returning a piece for every token and every character between them.
+    // Longest first, the same as the old sliding window encoder: the longest tokens claim their text first, leftmost
+    // first and never overlapping what's already claimed, then shorter tokens fill in around them (ties go to the lower id).
+    // Matches come out of the matcher in the order they end, and are settled a group at a time as soon as nothing
+    // after them can overlap them (see settled_prefix()), so only a few are ever held at once
+    fn find_tokens(&self, input: &str) -> Vec<Piece> {
+        let mut claimed: Vec<Located> = Vec::new();
+        if let Some(matcher) = &self.matcher { // None for an empty vocab
+            let mut pending: Vec<Located> = Vec::new();
+            let mut settle_at = RESOLVE_BATCH;
+            for m in matcher.find_overlapping_iter(input) {
+                pending.push((m.value(), m.start(), m.end()));
+                if pending.len() < settle_at {
+                    continue;
+                }
+
+                let reach = m.end().saturating_sub(self.longest_token); // no match after this one can start before here
+                if let Some(split) = settled_prefix(&pending, reach) {
+                    claim_longest(pending.drain(..split).collect(), &mut claimed);
+                } else if pending.len() > RESOLVE_LIMIT {
+                    // nowhere nothing overlaps (eg a long run of one character), so settle everything that ends by reach
+                    // and drop the matches that cross it. Only here can the tokens differ from claiming over the whole input
+                    let split = pending.partition_point(|&(_, _, end)| end <= reach);
+                    let cut = pending[..split].last().map_or(0, |&(_, _, end)| end);
+                    claim_longest(pending.drain(..split).collect(), &mut claimed);
+                    pending.retain(|&(_, start, _)| start >= cut);
                 }
+                settle_at = pending.len() + RESOLVE_BATCH;
             }
+            claim_longest(pending, &mut claimed);
+        }
+
+        let mut pieces = Vec::new();
+        let mut covered = 0;
+        for (index, start, end) in claimed {
+            unknown_pieces(input, covered, start, &mut pieces); // anything between two tokens isn't covered by the token set
+            pieces.push((Some(index), start, end));
+            covered = end;
+        }
+        unknown_pieces(input, covered, input.len(), &mut pieces);
+        pieces
+    }
+
+    pub fn get_tokens_from_text(&self, text: &str) -> Result<Vec<usize>> {
+        Ok(self.split_special(text, |segment| self.encode_pieces(self.pre_tokenise(segment), |input| self.find_tokens(input)))?)
+    }
+
+    // Applies the unknown policy to pieces with no token
+    fn resolve_unknown(&self, input: &str, pieces: Vec<Piece>) -> std::result::Result<Vec<Located>, UnencodableInput> {
+        let mut tokens = Vec::with_capacity(pieces.len());
+        let mut spans: Vec<(usize, usize, String)> = Vec::new();
+
+        for (index, start, end) in pieces {
+            if let Some(index) = index {
+                tokens.push((index, start, end));
+                continue;
+            }
+
+            match self.unknown_policy {
+                UnknownPolicy::Skip => {},
+                UnknownPolicy::Token => tokens.push((self.vocab_map[UNKNOWN_TOKEN], start, end)),
+                UnknownPolicy::Bytes => {
+                    let first = self.byte_fallback.unwrap_or_default();
+                    tokens.extend(input[start..end].bytes().zip(start..).map(|(byte, at)| (first + byte as usize, at, at + 1)));
+                },
+                UnknownPolicy::Error => match spans.last_mut() {
+                    Some(span) if span.1 == start => { // neighbouring characters are reported as one span
+                        span.1 = end;
+                        span.2.push_str(&input[start..end]);
+                    },
+                    _ => spans.push((start, end, input[start..end].to_string())),
+                },
+            }
+        }
+
+        if spans.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(UnencodableInput { spans })
         }
-        result.into_iter().map(|index| self.vocab[index.0].clone()).collect() // map the index to the token
     }
 
-    pub fn get_tokens_from_text(&self, text: &String) -> Vec<usize> {
+    // The original sliding window encoder - kept around to benchmark the matcher against. Only there with the
+    // bench feature (cargo bench --features bench), it isn't part of the API
+    #[cfg(any(test, feature = "bench"))]
+    #[doc(hidden)]
+    pub fn _get_tokens_from_text_sliding(&self, text: &str) -> Vec<usize> {
         // same process as tokenise()
-        let input = text.to_ascii_lowercase(); // only trained on lowercase letters
+        let input = self.normaliser.normalise(text).into_owned();
 
         if input.is_empty() { // Default cases
             return Vec::new();
         } else if input.len() == 1 {
-            return vec![*self.vocab_map.get(&input).unwrap_or(&(0 as usize))];
+            return vec![*self.vocab_map.get(&input).unwrap_or(&0)];
         }
 
         let input_size = input.len();
@@ -182,8 +616,8 @@ impl Tokeniser {
             for i in 0..=position.len() - window_size { // create the window
                 let window = &position[i..i + window_size]; // slide window across
                 if window.iter().zip(token.chars()).all(|(&(c, b), t)| c == t && b.is_none()) { // check if the token matches
-                    for index in i..i + window_size {
-                        position[index].1 = Some((token_index, count)); // Mark with the token index
+                    for item in &mut position[i..i + window_size] {
+                        item.1 = Some((token_index, count)); // Mark with the token index
                     }
                     count += 1;
                 }
@@ -205,43 +639,1168 @@ impl Tokeniser {
         result.into_iter().map(|(a, _)| a).collect()
     }
 
-    pub fn reconstruct(&self, tokens: &[usize]) -> String {
-        /// Similar process to recreate_string()  but this is for reconstructing tokens called from outside the tokeniser
-        tokens.into_iter().map(|index| self.vocab[*index].clone()).collect()
+    // Encodes the way the Trainer trained: start from single characters and keep applying the lowest ranked merge
+    // until no adjacent pair has a merge left. Unlike get_tokens_from_text this never picks a segmentation the model wouldn't.
+    // Vocabularies saved without merges (like the bundled ones) fall back to get_tokens_from_text
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        Ok(self.encode_segment(text)?)
+    }
+
+    // encode(), along with where in text each token came from
+    pub fn encode_with_offsets(&self, text: &str) -> Result<Vec<TokenOffsets>> {
+        let mut located = Vec::new();
+        let mut covered = 0;
+        let locate_segment = |start: usize, end: usize| -> std::result::Result<Vec<Located>, UnencodableInput> {
+            let tokens = self.locate_segment(&text[start..end]).map_err(|e| e.shifted(start))?;
+            Ok(tokens.into_iter().map(|(index, token_start, token_end)| (index, start + token_start, start + token_end)).collect())
+        };
+        for (index, start, end) in self.find_special(text) {
+            located.extend(locate_segment(covered, start)?);
+            located.push((index, start, end));
+            covered = end;
+        }
+        located.extend(locate_segment(covered, text.len())?);
+
+        // every offset is on a character boundary, so this is the char offset at each of them
+        let mut chars = vec![0; text.len() + 1];
+        for (count, (offset, _)) in text.char_indices().enumerate() {
+            chars[offset] = count;
+        }
+        chars[text.len()] = text.chars().count();
+
+        Ok(located.into_iter()
+            .map(|(id, start, end)| TokenOffsets { id, bytes: start..end, chars: chars[start]..chars[end] })
+            .collect())
+    }
+
+    // Encodes text with no special tokens in it, with byte ranges into text
+    fn locate_segment(&self, text: &str) -> std::result::Result<Vec<Located>, UnencodableInput> {
+        let (normalised, alignment) = self.normaliser.normalise_aligned(text);
+        let tokens = self.locate_pieces(self.pre_tokenise_normalised(&normalised), |input| self.match_pieces(input))?;
+
+        // byte level symbols are one character per normalised byte, but not one byte
+        let symbol_offsets: Vec<usize> = if self.byte_level {
+            normalised.bytes().chain([0])
+                .scan(0, |offset, byte| {
+                    let start = *offset;
+                    *offset += byte_level::symbol(byte).len_utf8();
+                    Some(start)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let to_normalised = |offset: usize| if self.byte_level { symbol_offsets.partition_point(|&start| start < offset) } else { offset };
+
+        // a token starts at the start of the stretch of text its first byte was normalised from, and ends at the end of
+        // the one its last byte was. Text normalised away to nothing (eg a stripped newline) isn't part of either token next to it
+        Ok(tokens.into_iter()
+            .map(|(index, start, end)| {
+                let (start, end) = (to_normalised(start), to_normalised(end));
+                let original_start = alignment[alignment.partition_point(|&(normalised, _)| normalised <= start) - 1].1;
+                let original_end = alignment[alignment.partition_point(|&(normalised, _)| normalised < end)].1;
+                (index, original_start, original_end)
+            })
+            .collect())
+    }
+
+    // encode() with everything about each token: its id, the token itself and where in text it came from.
+    // Owns all of it, so nothing is kept on the Tokeniser and one can be shared by any number of threads
+    pub fn encoding(&self, text: &str) -> Result<Encoding> {
+        let mut encoding = Encoding::default();
+        for token in self.encode_with_offsets(text)? {
+            encoding.ids.push(token.id);
+            encoding.tokens.push(self.vocab[token.id].clone());
+            encoding.offsets.push(token.bytes);
+            encoding.char_offsets.push(token.chars);
+        }
+        Ok(encoding)
+    }
+
+    // encode() for every text at once, spread over rayon's threads. Ids are u32 the way models take them
+    pub fn encode_batch(&self, texts: &[&str]) -> Result<Vec<Vec<u32>>> {
+        texts.par_iter()
+            .map(|text| Ok(self.encode(text)?.into_iter().map(|index| index as u32).collect())) // vocabularies are nowhere near u32::MAX tokens
+            .collect()
+    }
+
+    // encode_batch(), truncated and padded ready to feed a model
+    pub fn encode_batch_for_model(&self, texts: &[&str], truncation: Option<Truncation>, padding: Option<Padding>) -> Result<Vec<ModelInput>> {
+        prepare_batch(&self.encode_batch(texts)?, truncation, padding)
     }
 
-    pub fn pretty_print(&self) {
-        if self.decoded.is_none() {
-            println!("Text not yet tokenized");
-            return
+    // reconstruct() for every list of ids at once
+    pub fn decode_batch(&self, batch: &[Vec<u32>]) -> Result<Vec<String>> {
+        batch.par_iter()
+            .map(|ids| self.reconstruct(&ids.iter().map(|&id| id as usize).collect::<Vec<_>>()))
+            .collect()
+    }
+
+    // Same as encode() but for arbitrary bytes. With a byte level vocab nothing is ever unknown.
+    // Special tokens aren't looked for here since the input isn't necessarily text
+    pub fn encode_bytes(&self, bytes: &[u8]) -> Result<Vec<usize>> {
+        if self.byte_level {
+            Ok(self.encode_bytes_segment(bytes)?)
+        } else {
+            self.encode(&String::from_utf8_lossy(bytes))
         }
+    }
+
+    // Encodes everything the reader gives without holding it all in memory, writing the ids to the writer as they're
+    // found (space separated, the same as the CLI prints them). Returns how many tokens were written.
+    // The ids are the same as encode() (or encode_bytes() for a byte level vocab) would give for the whole input at once,
+    // unless STREAM_LIMIT of input goes by with nowhere safe to cut. Then it's cut at whitespace anyway (see forced_cut()),
+    // which can split a token that would have crossed that whitespace
+    pub fn encode_stream<R: BufRead, W: Write>(&self, reader: R, mut writer: W) -> Result<usize> {
+        let mut written = 0;
+        self.encode_chunks(reader, STREAM_CHUNK, |tokens| {
+            for &index in tokens {
+                if written > 0 {
+                    writer.write_all(b" ")?;
+                }
+                write!(writer, "{}", index)?;
+                written += 1;
+            }
+            Ok(())
+        })?;
+
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    // encode_stream() but writing the compact binary format from token_file
+    pub fn encode_stream_binary<R: BufRead, W: Write>(&self, reader: R, writer: W) -> Result<usize> {
+        let mut writer = TokenWriter::new(writer, Header::for_tokeniser(self))?;
+        self.encode_chunks(reader, STREAM_CHUNK, |tokens| writer.write(tokens))?;
+
+        let written = writer.written();
+        writer.finish()?;
+        Ok(written)
+    }
 
-        for token in self.decoded.as_ref().unwrap() {
-            let token_index = self.vocab_map.get(token).unwrap_or(&usize::max_value()); // if token not encountered make it white
-            let token_colour = self.colour_map.get(token_index).unwrap_or(&(0, 0, 0));
+    // Reads chunk_size bytes or so at a time and hands each batch of finished tokens to emit. The buffered input is only
+    // ever cut where no token could span the cut (see stream_cut()), so a token is never split between two batches.
+    // Input with nowhere safe to cut keeps buffering until there is somewhere, or until there's STREAM_LIMIT of it.
+    // Returns the most input that was ever buffered at once
+    fn encode_chunks<R, F>(&self, mut reader: R, chunk_size: usize, mut emit: F) -> Result<usize>
+    where
+        R: BufRead,
+        F: FnMut(&[usize]) -> Result<()>,
+    {
+        let joinable = self.joinable_pairs();
+        let mut prepared = HashMap::new(); // see prepared_ends()
+        let mut bytes = Vec::new(); // read but not encoded yet for a byte level vocab, otherwise the start of a character cut off at the end of the last read
+        let mut text = String::new(); // read but not encoded yet, for any other vocab
+        let mut checked = 0; // every cut up to here has already been looked at and isn't safe, so only what's been read since is scanned
+        let mut encoded = 0; // how much of the input has been handed out, so errors point at the right place
+        let mut peak = 0;
 
-            print!("{}", token.as_str().custom_color(CustomColor {
-                r: token_colour.0,
-                g: token_colour.1,
-                b: token_colour.2,
-            }));
+        loop {
+            let chunk = reader.fill_buf()?;
+            let done = chunk.is_empty();
+            let length = chunk.len();
+            bytes.extend_from_slice(chunk);
+            if !self.byte_level {
+                push_utf8(&mut text, &mut bytes, done);
+            }
+            reader.consume(length);
+
+            let buffered = if self.byte_level { bytes.len() } else { text.len() };
+            peak = peak.max(buffered);
+            if !done && buffered < chunk_size {
+                continue;
+            }
+
+            if self.byte_level {
+                let cut = if done {
+                    Some(bytes.len())
+                } else {
+                    self.byte_stream_cut(&bytes, checked, &joinable)
+                        .or_else(|| (bytes.len() > STREAM_LIMIT).then(|| forced_byte_cut(&bytes)))
+                };
+                if let Some(cut) = cut {
+                    emit(&self.encode_bytes_segment(&bytes[..cut]).map_err(|e| e.shifted(encoded))?)?;
+                    encoded += bytes[..cut].iter().map(|&byte| byte_level::symbol(byte).len_utf8()).sum::<usize>(); // spans are over the byte characters
+                    bytes.drain(..cut);
+                }
+                checked = bytes.len().saturating_sub(1); // the last byte has nothing after it to check against yet
+            } else {
+                let cut = if done {
+                    Some(text.len())
+                } else {
+                    self.stream_cut(&text, checked, &joinable, &mut prepared)
+                        .or_else(|| if text.len() > STREAM_LIMIT { self.forced_cut(&text) } else { None })
+                };
+                if let Some(cut) = cut {
+                    emit(&self.encode_segment(&text[..cut]).map_err(|e| e.shifted(encoded))?)?;
+                    text.drain(..cut);
+                    encoded += cut;
+                }
+                checked = text.char_indices().next_back().map_or(0, |(last, _)| last);
+            }
+
+            if done {
+                return Ok(peak);
+            }
         }
-        println!();
     }
 
-    pub fn _compare_to_original(&mut self, original_string: String, tokenized_data: Vec<String>) {
-        for token in tokenized_data {
-            let token_index = self.vocab_map.get(&token).unwrap_or(&usize::max_value()); // if token not encountered make it white
-            let token_colour = self.colour_map.get(token_index).unwrap_or(&(0, 0, 0));
+    // Every pair of characters that sit next to each other inside some token. Two pieces can only ever be merged
+    // (or matched as one token) across a point in the input where the characters either side are one of these pairs
+    fn joinable_pairs(&self) -> HashSet<(char, char)> {
+        let mut pairs = HashSet::new();
+        for token in &self.vocab {
+            let chars: Vec<char> = token.chars().collect();
+            pairs.extend(chars.windows(2).map(|pair| (pair[0], pair[1])));
+        }
+        pairs
+    }
+
+    // The first and last symbol a character becomes once it's prepared, None if normalising drops it.
+    // Cached, since stream_cut() asks about the same few characters over and over
+    fn prepared_ends(&self, c: char, cache: &mut HashMap<char, Option<(char, char)>>) -> Option<(char, char)> {
+        *cache.entry(c).or_insert_with(|| {
+            let prepared = self.prepare(c.encode_utf8(&mut [0; 4]));
+            prepared.chars().next().zip(prepared.chars().next_back())
+        })
+    }
 
-            print!("{}", token.as_str().custom_color(CustomColor {
-                r: token_colour.0,
-                g: token_colour.1,
-                b: token_colour.2,
-            }));
+    // The last point in text that's safe to encode up to on its own: no token can span it and it isn't inside a special token.
+    // The very end isn't safe since the next read could continue the last token. Cuts up to checked were already
+    // found unsafe on an earlier read, so only the ones after it are looked at
+    fn stream_cut(&self, text: &str, checked: usize, joinable: &HashSet<(char, char)>, prepared: &mut HashMap<char, Option<(char, char)>>) -> Option<usize> {
+        if !self.pre_tokeniser.is_none() {
+            return self.piece_cut(text);
         }
-        println!();
-        self.tokenise(&original_string);
-        self.pretty_print();
-    }   
+
+        let mut chars = text.char_indices().rev().take_while(|&(cut, _)| cut >= checked).peekable();
+        while let Some((cut, after)) = chars.next() {
+            let before = chars.peek()?.1;
+            if cut <= checked {
+                break;
+            }
+
+            if !self.normaliser.separable(before, after) {
+                continue;
+            }
+
+            // normalising can drop a character (stripped newlines), which would bring the characters either side together
+            let (Some((_, before)), Some((after, _))) = (self.prepared_ends(before, prepared), self.prepared_ends(after, prepared)) else {
+                continue;
+            };
+
+            if !joinable.contains(&(before, after)) && !self.ends_in_special(&text[..cut]) {
+                return Some(cut);
+            }
+        }
+        None
+    }
+
+    // stream_cut() for the raw bytes a byte level vocab is streamed as
+    fn byte_stream_cut(&self, bytes: &[u8], checked: usize, joinable: &HashSet<(char, char)>) -> Option<usize> {
+        if !self.pre_tokeniser.is_none() {
+            let pieces = byte_pieces(&self.pre_tokeniser, bytes); // the last two are held back, the same as piece_cut()
+            return pieces.len().checked_sub(2).map(|last| pieces[last].0).filter(|&cut| cut > 0);
+        }
+
+        (checked + 1..bytes.len()).rev().find(|&cut| !joinable.contains(&(byte_level::symbol(bytes[cut - 1]), byte_level::symbol(bytes[cut]))))
+    }
+
+    // A cut for when there's been nowhere safe to cut for STREAM_LIMIT of input: just after the last whitespace near
+    // the end, or any point near the end if there's no whitespace. Still never inside a special token
+    fn forced_cut(&self, text: &str) -> Option<usize> {
+        let mut chars = text.char_indices().rev().take_while(|&(cut, _)| cut + FORCED_LOOKBACK >= text.len()).peekable();
+        let mut fallback = None;
+        while let Some((cut, after)) = chars.next() {
+            let Some(&(_, before)) = chars.peek() else {
+                break;
+            };
+            if !self.normaliser.separable(before, after) || self.ends_in_special(&text[..cut]) {
+                continue;
+            }
+            if before.is_whitespace() && !after.is_whitespace() {
+                return Some(cut);
+            }
+            fallback = fallback.or(Some(cut));
+        }
+        fallback
+    }
+
+    // With a pre-tokeniser every piece is encoded on its own, so any piece boundary is a safe cut. Except near the end,
+    // where more input can still change how the last pieces split (more letters on a word, trailing whitespace that
+    // gives its last space to the next word), so the last two pieces are held back
+    fn piece_cut(&self, text: &str) -> Option<usize> {
+        // special tokens are cut out before pre-tokenising, so only the text after the last one splits the way it does here
+        let segment_start = match &self.special_matcher {
+            Some(matcher) if self.allow_special => matcher.leftmost_find_iter(text).last().map_or(0, |m| m.end()),
+            _ => 0,
+        };
+        let segment = &text[segment_start..];
+        let normalised = self.normaliser.normalise(segment);
+        let pieces = self.pre_tokeniser.split(&normalised);
+
+        // the pieces are over the normalised text, so walk back through the raw text to find where each one starts.
+        // Only points where normalising either side on its own changes nothing have a place in the normalised text
+        let mut starts = pieces.iter().rev().skip(1).map(|&(start, _)| start).filter(|&start| start > 0).peekable();
+        let mut chars = segment.char_indices().rev().peekable();
+        let mut offset = normalised.len(); // where separable would be in the normalised text
+        let mut separable = segment.len();
+        while let Some((position, after)) = chars.next() {
+            let Some(&(_, before)) = chars.peek() else {
+                break;
+            };
+            if !self.normaliser.separable(before, after) {
+                continue;
+            }
+            offset -= self.normaliser.normalise(&segment[position..separable]).len();
+            separable = position;
+
+            while starts.next_if(|&start| start > offset).is_some() {}
+            let Some(&start) = starts.peek() else {
+                break;
+            };
+            let cut = segment_start + position;
+            if start == offset && !self.ends_in_special(&text[..cut]) {
+                return Some(cut);
+            }
+        }
+
+        (segment_start > 0 && !self.ends_in_special(&text[..segment_start])).then_some(segment_start)
+    }
+
+    // Whether text ends part way through a special token, so cutting there would stop it being matched
+    fn ends_in_special(&self, text: &str) -> bool {
+        self.allow_special && self.special_tokens.keys().any(|token| {
+            token.char_indices().skip(1).any(|(end, _)| text.ends_with(&token[..end]))
+        })
+    }
+
+    // Merge replay if there are merges, otherwise the longest match
+    fn match_pieces(&self, input: &str) -> Vec<Piece> {
+        if self.merges.is_empty() {
+            self.find_tokens(input)
+        } else {
+            self.apply_merges(input)
+        }
+    }
+
+    fn encode_segment(&self, text: &str) -> std::result::Result<Vec<usize>, UnencodableInput> {
+        self.split_special(text, |segment| self.encode_pieces(self.pre_tokenise(segment), |input| self.match_pieces(input)))
+    }
+
+    // encode_bytes() for a byte level vocab. Valid UTF-8 is pre-tokenised like text, and each run of invalid bytes is a piece of its own
+    fn encode_bytes_segment(&self, bytes: &[u8]) -> std::result::Result<Vec<usize>, UnencodableInput> {
+        let pieces = byte_pieces(&self.pre_tokeniser, bytes).into_iter().map(|(start, end)| byte_level::encode(&bytes[start..end]));
+        self.encode_pieces(pieces, |input| self.match_pieces(input))
+    }
+
+    // The pieces ModelConfig::pieces() gives, without cloning the config
+    fn pre_tokenise(&self, text: &str) -> Vec<String> {
+        self.pre_tokenise_normalised(&self.normaliser.normalise(text))
+    }
+
+    fn pre_tokenise_normalised(&self, text: &str) -> Vec<String> {
+        if self.pre_tokeniser.is_none() {
+            return vec![self.prepare_normalised(text)];
+        }
+        self.pre_tokeniser.split(text).into_iter().map(|(start, end)| self.prepare_normalised(&text[start..end])).collect()
+    }
+
+    fn encode_pieces<F>(&self, pieces: impl IntoIterator<Item = String>, match_piece: F) -> std::result::Result<Vec<usize>, UnencodableInput>
+    where
+        F: Fn(&str) -> Vec<Piece>,
+    {
+        Ok(self.locate_pieces(pieces, match_piece)?.into_iter().map(|(index, _, _)| index).collect())
+    }
+
+    // Encodes each piece (already in the vocab's symbols) on its own so no token spans two of them.
+    // Token and unknown spans are offsets into all the pieces put back together
+    fn locate_pieces<F>(&self, pieces: impl IntoIterator<Item = String>, match_piece: F) -> std::result::Result<Vec<Located>, UnencodableInput>
+    where
+        F: Fn(&str) -> Vec<Piece>,
+    {
+        let mut tokens = Vec::new();
+        let mut spans: Vec<(usize, usize, String)> = Vec::new();
+        let mut offset = 0;
+
+        for piece in pieces {
+            match self.resolve_unknown(&piece, match_piece(&piece)) {
+                Ok(piece_tokens) => tokens.extend(piece_tokens.into_iter().map(|(index, start, end)| (index, offset + start, offset + end))),
+                Err(unencodable) => {
+                    for (start, end, text) in unencodable.shifted(offset).spans {
+                        match spans.last_mut() {
+                            Some(span) if span.1 == start => { // the same joining resolve_unknown() does, across pieces
+                                span.1 = end;
+                                span.2.push_str(&text);
+                            },
+                            _ => spans.push((start, end, text)),
+                        }
+                    }
+                },
+            }
+            offset += piece.len();
+        }
+
+        if spans.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(UnencodableInput { spans })
+        }
+    }
+
+    // Starts from single characters as a linked list of token ids, with every adjacent pair that has a merge in a heap.
+    // The lowest ranked merge is applied everywhere it occurs, left to right, before anything ranked after it,
+    // the same as merge_pair() does in training. Only the neighbours of a merge are looked at again
+    fn apply_merges(&self, input: &str) -> Vec<Piece> {
+        let mut buffer = [0; 4];
+        let mut symbols: Vec<Symbol> = input.char_indices()
+            .enumerate()
+            .map(|(position, (start, c))| Symbol {
+                id: self.vocab_map.get(&*c.encode_utf8(&mut buffer)).map_or(UNKNOWN_SYMBOL, |&index| index as u32),
+                start,
+                end: start + c.len_utf8(),
+                prev: if position == 0 { NO_SYMBOL } else { position - 1 },
+                next: position + 1, // fixed up for the last one below
+            })
+            .collect();
+        if let Some(last) = symbols.last_mut() {
+            last.next = NO_SYMBOL;
+        }
+
+        let mut queue = BinaryHeap::new(); // Reverse((rank, left symbol)), so the lowest rank and then the leftmost comes out first
+        for left in 0..symbols.len().saturating_sub(1) {
+            self.queue_pair(&symbols, left, &mut queue);
+        }
+
+        let mut round = Vec::new();
+        while let Some(Reverse((rank, left))) = queue.pop() {
+            round.clear();
+            round.push(left);
+            while let Some(&Reverse((next_rank, next_left))) = queue.peek() {
+                if next_rank != rank {
+                    break;
+                }
+                round.push(next_left);
+                queue.pop();
+            }
+            round.sort_unstable(); // symbols keep their first character's position, so this is left to right
+
+            for &left in &round {
+                // entries go stale once either side has been merged into something else
+                let right = symbols[left].next;
+                if symbols[left].id == MERGED_SYMBOL || right == NO_SYMBOL {
+                    continue;
+                }
+                let Some(&(pair_rank, merged)) = self.merge_ranks.get(&(symbols[left].id, symbols[right].id)) else {
+                    continue;
+                };
+                if pair_rank != rank {
+                    continue;
+                }
+
+                let after = symbols[right].next;
+                symbols[left].id = merged;
+                symbols[left].end = symbols[right].end;
+                symbols[left].next = after;
+                symbols[right].id = MERGED_SYMBOL;
+                if after != NO_SYMBOL {
+                    symbols[after].prev = left;
+                }
+
+                if symbols[left].prev != NO_SYMBOL {
+                    self.queue_pair(&symbols, symbols[left].prev, &mut queue);
+                }
+                self.queue_pair(&symbols, left, &mut queue);
+            }
+        }
+
+        let mut pieces = Vec::new();
+        let mut position = if symbols.is_empty() { NO_SYMBOL } else { 0 };
+        while position != NO_SYMBOL {
+            let symbol = &symbols[position];
+            let index = (symbol.id != UNKNOWN_SYMBOL).then_some(symbol.id as usize); // merges only make vocab tokens, so only single characters can be missing
+            pieces.push((index, symbol.start, symbol.end));
+            position = symbol.next;
+        }
+        pieces
+    }
+
+    fn queue_pair(&self, symbols: &[Symbol], left: usize, queue: &mut BinaryHeap<Reverse<(u32, usize)>>) {
+        let right = symbols[left].next;
+        if right == NO_SYMBOL {
+            return;
+        }
+        if let Some(&(rank, _)) = self.merge_ranks.get(&(symbols[left].id, symbols[right].id)) {
+            queue.push(Reverse((rank, left)));
+        }
+    }
+
+    pub fn reconstruct(&self, tokens: &[usize]) -> Result<String> {
+        // Maps each index back to its token - for reconstructing tokens called from outside the tokeniser
+        Ok(String::from_utf8_lossy(&self.reconstruct_bytes(tokens)?).into_owned())
+    }
+
+    // The exact bytes the tokens stand for. Only differs from reconstruct() for input that wasn't valid UTF-8.
+    // Ids come from outside (files, stdin, other tools), so one past the vocab is an error rather than a panic
+    pub fn reconstruct_bytes(&self, tokens: &[usize]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for &index in tokens {
+            if index >= self.vocab.len() {
+                return Err(Error::UnknownId { id: index, vocab_size: self.vocab.len() });
+            }
+            match self.byte_fallback {
+                Some(first) if (first..first + 256).contains(&index) => output.push((index - first) as u8),
+                _ if self.is_special(index) => output.extend_from_slice(self.vocab[index].as_bytes()),
+                _ if self.byte_level => output.extend(byte_level::decode(&self.vocab[index])),
+                _ => output.extend_from_slice(self.vocab[index].as_bytes()),
+            }
+        }
+        Ok(output)
+    }
+}
+
+// Names of the vocabularies built in from output/ that Tokeniser::bundled() can load
+pub fn bundled_vocabularies() -> Vec<String> {
+    BUNDLED.iter().map(|&(name, _)| name.to_string()).collect()
+}
+
+// Names of the vocabularies in dir that Tokeniser::from_dir() can load
+pub fn vocabularies_in<P: AsRef<Path>>(dir: P) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if let Some(stem) = name.strip_suffix(".json") {
+            if stem != "initial_vocab" && !stem.ends_with(".merges") && !stem.ends_with(".config") { // character counts only, merge lists and configs
+                names.push(stem.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// vocabulary.json -> vocabulary.merges.json, in the same directory
+pub fn merges_path_for(vocab_path: &Path) -> PathBuf {
+    let stem = vocab_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("vocabulary");
+    vocab_path.with_file_name(format!("{}.merges.json", stem))
+}
+
+// vocabulary.json -> vocabulary.config.json, in the same directory
+pub fn config_path_for(vocab_path: &Path) -> PathBuf {
+    let stem = vocab_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("vocabulary");
+    vocab_path.with_file_name(format!("{}.config.json", stem))
+}
+
+// Matcher over (token, index) pairs, None if there are no tokens to match. The vocab needs every overlapping match
+// (MatchKind::Standard), special tokens just the leftmost-longest ones
+fn token_matcher<'a>(tokens: impl Iterator<Item = (&'a str, usize)>, kind: MatchKind) -> Option<CharwiseDoubleArrayAhoCorasick<usize>> {
+    CharwiseDoubleArrayAhoCorasickBuilder::new()
+        .match_kind(kind)
+        .build_with_values(tokens.filter(|(token, _)| !token.is_empty()))
+        .ok()
+}
+
+// forced_cut() for a byte level vocab's raw bytes. Any byte is somewhere to cut, so there's always one
+fn forced_byte_cut(bytes: &[u8]) -> usize {
+    let lookback = bytes.len().saturating_sub(FORCED_LOOKBACK).max(1);
+    (lookback..bytes.len()).rev()
+        .find(|&cut| bytes[cut - 1].is_ascii_whitespace() && !bytes[cut].is_ascii_whitespace())
+        .unwrap_or(bytes.len())
+}
+
+// How many of the matches (in the order they end) can be settled on their own: the longest run that ends at or before
+// reach with nothing after it starting before it ends. None if no such run
+fn settled_prefix(pending: &[Located], reach: usize) -> Option<usize> {
+    let mut first_start = usize::MAX; // earliest start of everything after the run
+    for split in (1..=pending.len()).rev() {
+        let end = pending[split - 1].2;
+        if end <= reach && end <= first_start {
+            return Some(split);
+        }
+        first_start = first_start.min(pending[split - 1].1);
+    }
+    None
+}
+
+// Settles a group of matches nothing else overlaps: longest first, then lowest id, then leftmost, each one claimed if
+// none of its text is claimed yet. The claims are added to claimed in order
+fn claim_longest(mut group: Vec<Located>, claimed: &mut Vec<Located>) {
+    let Some(first) = group.iter().map(|&(_, start, _)| start).min() else {
+        return;
+    };
+    let last = group.iter().map(|&(_, _, end)| end).max().unwrap_or(first);
+    group.sort_unstable_by_key(|&(index, start, end)| (Reverse(end - start), index, start));
+
+    let mut taken = vec![false; last - first];
+    let from = claimed.len();
+    for (index, start, end) in group {
+        let span = &mut taken[start - first..end - first];
+        if !span.contains(&true) {
+            span.fill(true);
+            claimed.push((index, start, end));
+        }
+    }
+    claimed[from..].sort_unstable_by_key(|&(_, start, _)| start);
+}
+
+// The pre-tokeniser's pieces over bytes that might not be UTF-8: valid runs split like text, each invalid run is a piece of its own
+fn byte_pieces(pre_tokeniser: &PreTokeniser, bytes: &[u8]) -> Vec<(usize, usize)> {
+    if pre_tokeniser.is_none() {
+        return vec![(0, bytes.len())];
+    }
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for chunk in bytes.utf8_chunks() {
+        let valid = chunk.valid();
+        pieces.extend(pre_tokeniser.split(valid).into_iter().map(|(piece_start, piece_end)| (start + piece_start, start + piece_end)));
+        start += valid.len();
+
+        let invalid = chunk.invalid().len();
+        if invalid > 0 {
+            pieces.push((start, start + invalid));
+            start += invalid;
+        }
+    }
+    pieces
+}
+
+// One unknown piece per character in input[start..end]
+fn unknown_pieces(input: &str, start: usize, end: usize, pieces: &mut Vec<Piece>) {
+    for (offset, c) in input[start..end].char_indices() {
+        pieces.push((None, start + offset, start + offset + c.len_utf8()));
+    }
+}
+
+// Moves the complete characters in bytes onto text, leaving a character cut off at the end in bytes for the next read.
+// Invalid UTF-8 becomes U+FFFD the same way String::from_utf8_lossy() does it
+pub(crate) fn push_utf8(text: &mut String, bytes: &mut Vec<u8>, done: bool) {
+    let mut start = 0;
+    loop {
+        let error = match std::str::from_utf8(&bytes[start..]) {
+            Ok(valid) => {
+                text.push_str(valid);
+                start = bytes.len();
+                break;
+            },
+            Err(error) => error,
+        };
+
+        let valid_end = start + error.valid_up_to();
+        text.push_str(&String::from_utf8_lossy(&bytes[start..valid_end])); // all valid, so nothing is replaced
+        match error.error_len() {
+            Some(length) => start = valid_end + length,
+            None if done => start = bytes.len(), // the input ended part way through a character
+            None => {
+                start = valid_end;
+                break;
+            },
+        }
+        text.push(char::REPLACEMENT_CHARACTER);
+    }
+    bytes.drain(..start);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normaliser::UnicodeForm;
+    use crate::trainer::Trainer;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const CASES: usize = 300;
+    const TRAINING: &str = "The cat sat on the mat.\nThe rat sat on the hat!\n\tA cat, a rat & a hat: \"that's that\" (1, 2, 3)";
+
+    fn byte_level_tokeniser() -> Tokeniser {
+        let mut trainer = Trainer::new(byte_level::corpus(TRAINING.as_bytes()), HashMap::new()).with_alphabet(&byte_level::alphabet());
+        trainer.train(320).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+        Tokeniser::from_vocab(&vocab, merges).with_byte_level(true)
+    }
+
+    fn char_level_tokeniser() -> Tokeniser {
+        let corpus: Vec<String> = TRAINING.chars().map(|c| c.to_string()).collect();
+        let initial_vocab = crate::vocab::initialize_vocab(&corpus);
+        let mut trainer = Trainer::new(corpus, initial_vocab);
+        trainer.train(80).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+        Tokeniser::from_vocab(&vocab, merges)
+    }
+
+    // Mostly pieces of the training text so merges actually fire, mixed with anything at all
+    fn random_text(rng: &mut StdRng, alphabet: &[char]) -> String {
+        let length = rng.gen_range(0..60);
+        (0..length).map(|_| {
+            if alphabet.is_empty() || rng.gen_bool(0.2) { rng.gen::<char>() } else { alphabet[rng.gen_range(0..alphabet.len())] }
+        }).collect()
+    }
+
+    #[test]
+    fn byte_level_round_trips_any_text() {
+        let tokeniser = byte_level_tokeniser().with_lossless();
+        assert!(tokeniser.is_lossless());
+
+        let alphabet: Vec<char> = TRAINING.chars().chain("日本語 🦀 é\r\n\0".chars()).collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let input = random_text(&mut rng, &alphabet);
+            assert_eq!(tokeniser.reconstruct(&tokeniser.encode(&input).unwrap()).unwrap(), input);
+            assert_eq!(tokeniser.reconstruct(&tokeniser.get_tokens_from_text(&input).unwrap()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn byte_level_round_trips_any_bytes() {
+        let tokeniser = byte_level_tokeniser();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let length = rng.gen_range(0..60);
+            let input: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            assert_eq!(tokeniser.reconstruct_bytes(&tokeniser.encode_bytes(&input).unwrap()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn char_level_round_trips_its_alphabet() {
+        let tokeniser = char_level_tokeniser();
+        let alphabet: Vec<char> = TRAINING.chars().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let input: String = (0..rng.gen_range(0..60)).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect();
+            assert_eq!(tokeniser.reconstruct(&tokeniser.encode(&input).unwrap()).unwrap(), input);
+            assert_eq!(tokeniser.reconstruct(&tokeniser.get_tokens_from_text(&input).unwrap()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn lossless_char_level_round_trips_unseen_characters() {
+        let tokeniser = char_level_tokeniser().with_normaliser(Normaliser::text8()).with_lossless();
+        assert!(tokeniser.is_lossless());
+        assert!(!char_level_tokeniser().with_normaliser(Normaliser::none()).is_lossless()); // unseen characters would be skipped
+        assert!(!char_level_tokeniser().with_byte_level(true).is_lossless()); // byte level without the byte symbols can't encode them
+
+        let alphabet: Vec<char> = TRAINING.chars().chain("日本語 🦀 É\r\n\0".chars()).collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let input = random_text(&mut rng, &alphabet);
+            assert_eq!(tokeniser.reconstruct(&tokeniser.encode(&input).unwrap()).unwrap(), input);
+            assert_eq!(tokeniser.reconstruct(&tokeniser.get_tokens_from_text(&input).unwrap()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn normalisation_is_the_only_change() {
+        let normaliser = Normaliser::text8();
+        let tokeniser = byte_level_tokeniser().with_normaliser(normaliser);
+        assert!(!tokeniser.is_lossless());
+
+        let alphabet: Vec<char> = TRAINING.chars().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let input = random_text(&mut rng, &alphabet);
+            assert_eq!(tokeniser.reconstruct(&tokeniser.encode(&input).unwrap()).unwrap(), normaliser.normalise(&input));
+        }
+    }
+
+    #[test]
+    fn unknown_policies() {
+        let input = "the cat é🦀 sat";
+
+        let skip = char_level_tokeniser();
+        assert_eq!(skip.reconstruct(&skip.encode(input).unwrap()).unwrap(), "the cat  sat");
+
+        let token = char_level_tokeniser().with_unknown_policy(UnknownPolicy::Token);
+        let unknown = token.vocab_map[UNKNOWN_TOKEN];
+        let tokens = token.encode(input).unwrap();
+        assert_eq!(tokens.iter().filter(|&&index| index == unknown).count(), 2);
+        assert_eq!(token.reconstruct(&tokens).unwrap(), "the cat <unk><unk> sat");
+        assert_eq!(token.encode("<unk>").unwrap().len(), 5); // typing it out gives one <unk> per unknown character, never the reserved token itself
+
+        let bytes = char_level_tokeniser().with_unknown_policy(UnknownPolicy::Bytes);
+        assert_eq!(bytes.reconstruct(&bytes.encode(input).unwrap()).unwrap(), input);
+        assert_eq!(bytes.reconstruct(&bytes.get_tokens_from_text(input).unwrap()).unwrap(), input);
+
+        let error = char_level_tokeniser().with_unknown_policy(UnknownPolicy::Error);
+        let Err(Error::Unencodable(UnencodableInput { spans })) = error.encode(input) else {
+            panic!("expected the unknown characters to be reported");
+        };
+        assert_eq!(spans, vec![(8, 14, "é🦀".to_string())]);
+        assert!(error.get_tokens_from_text("the cat").is_ok());
+    }
+
+    #[test]
+    fn special_tokens_are_never_split() {
+        let tokeniser = byte_level_tokeniser().with_special_tokens(&[END_OF_TEXT, PAD_TOKEN]);
+        let end_of_text = tokeniser.special_token_id(END_OF_TEXT).unwrap();
+        let input = "the cat<|endoftext|>The mat<|pad|>";
+
+        let denied = tokeniser.encode(input).unwrap(); // off by default
+        assert!(!denied.contains(&end_of_text));
+        assert_eq!(tokeniser.reconstruct(&denied).unwrap(), input);
+
+        let tokeniser = tokeniser.with_allow_special(true);
+        let allowed = tokeniser.encode(input).unwrap();
+        assert_eq!(allowed.iter().filter(|&&index| index == end_of_text).count(), 1);
+        assert_eq!(allowed.last(), tokeniser.special_token_id(PAD_TOKEN).as_ref());
+        assert_eq!(tokeniser.reconstruct(&allowed).unwrap(), input);
+        assert_eq!(tokeniser.get_tokens_from_text(input).unwrap().iter().filter(|&&index| index == end_of_text).count(), 1);
+    }
+
+    #[test]
+    fn matcher_agrees_with_the_sliding_window_encoder() {
+        let tokeniser = Tokeniser::bundled(DEFAULT_BUNDLED).unwrap();
+        let sample = include_str!("../fixtures/sample.txt");
+        for text in sample.lines() {
+            assert_eq!(tokeniser.get_tokens_from_text(text).unwrap(), tokeniser._get_tokens_from_text_sliding(text));
+        }
+
+        // and on the same words shuffled, so tokens meet neighbours they never had in the sample
+        let words: Vec<&str> = sample.split_whitespace().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES / 10 {
+            let text: Vec<&str> = (0..40).map(|_| words[rng.gen_range(0..words.len())]).collect();
+            let text = text.join(" ");
+            assert_eq!(tokeniser.get_tokens_from_text(&text).unwrap(), tokeniser._get_tokens_from_text_sliding(&text));
+        }
+    }
+
+    #[test]
+    fn matching_a_long_run_settles_as_it_goes() {
+        // in a run of one character every match overlaps the next, so nothing is ever safe to settle early
+        let tokeniser = Tokeniser::from_tokens(vec!["aaa".to_string(), "aa".to_string(), "a".to_string()], Vec::new());
+        let input = "a".repeat(RESOLVE_LIMIT);
+        let tokens = tokeniser.get_tokens_from_text(&input).unwrap();
+        assert_eq!(tokeniser.reconstruct(&tokens).unwrap(), input);
+        assert!(tokens.len() < input.len() / 3 + 10, "{} tokens", tokens.len()); // all but a few around each forced settle are "aaa"
+    }
+
+    #[test]
+    fn streaming_matches_encoding_all_at_once() {
+        let bundled = Tokeniser::bundled(DEFAULT_BUNDLED).unwrap();
+        let special = char_level_tokeniser().with_special_tokens(&[END_OF_TEXT]).with_allow_special(true);
+        let alphabet: Vec<char> = TRAINING.chars().chain("<|endoftext|>🦀\n   12".chars()).collect();
+        let mut rng = StdRng::seed_from_u64(8);
+
+        // and with each pre-tokeniser, where cuts go between pieces instead
+        let gpt2 = byte_level_tokeniser().with_pre_tokeniser(PreTokeniser::gpt2());
+        let cl100k = char_level_tokeniser().with_pre_tokeniser(PreTokeniser::cl100k()).with_special_tokens(&[END_OF_TEXT]).with_allow_special(true);
+        let whitespace = Tokeniser::bundled(DEFAULT_BUNDLED).unwrap().with_pre_tokeniser(PreTokeniser::whitespace().with_split_digits(true));
+
+        for tokeniser in [byte_level_tokeniser(), char_level_tokeniser(), bundled, special, gpt2, cl100k, whitespace] {
+            for _ in 0..CASES / 10 {
+                let input: String = (0..10).map(|_| random_text(&mut rng, &alphabet)).collect();
+                let expected = if tokeniser.byte_level { tokeniser.encode_bytes(input.as_bytes()) } else { tokeniser.encode(&input) }.unwrap();
+
+                // tiny reads so characters, tokens and special tokens all get cut up between them
+                let reader = BufReader::with_capacity(rng.gen_range(1..8), input.as_bytes());
+                let mut streamed = Vec::new();
+                tokeniser.encode_chunks(reader, rng.gen_range(1..40), |tokens| {
+                    streamed.extend_from_slice(tokens);
+                    Ok(())
+                }).unwrap();
+                assert_eq!(streamed, expected);
+            }
+        }
+
+        let tokeniser = char_level_tokeniser();
+        let mut output = Vec::new();
+        assert_eq!(tokeniser.encode_stream("the cat".as_bytes(), &mut output).unwrap(), tokeniser.encode("the cat").unwrap().len());
+        let ids: Vec<usize> = String::from_utf8(output).unwrap().split_whitespace().map(|id| id.parse().unwrap()).collect();
+        assert_eq!(ids, tokeniser.encode("the cat").unwrap());
+    }
+
+    #[test]
+    fn streaming_holds_a_bounded_buffer() {
+        // almost every pair of letters (and letter and space) is inside some token of the 55k vocab, so ordinary
+        // text has nowhere safe to cut and only the forced cuts keep the buffer down
+        let tokeniser = Tokeniser::bundled("1M_words-55k_tokens").unwrap();
+        let sample = include_str!("../fixtures/sample.txt").replace('\n', " ");
+        let input = sample.repeat(3 * STREAM_LIMIT / sample.len());
+
+        let mut streamed = Vec::new();
+        let peak = tokeniser.encode_chunks(BufReader::new(input.as_bytes()), STREAM_CHUNK, |tokens| {
+            streamed.extend_from_slice(tokens);
+            Ok(())
+        }).unwrap();
+        assert!(peak <= STREAM_LIMIT + STREAM_CHUNK, "buffered {} bytes", peak);
+        assert_eq!(tokeniser.reconstruct(&streamed).unwrap(), tokeniser.normaliser().normalise(&input));
+    }
+
+    #[test]
+    fn tokens_never_cross_pieces() {
+        let config = ModelConfig { normaliser: Normaliser::none(), pre_tokeniser: PreTokeniser::gpt2(), byte_level: true };
+        let mut trainer = Trainer::from_text(TRAINING, &config);
+        trainer.train(400).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+        let tokeniser = Tokeniser::from_vocab(&vocab, merges).with_config(config.clone());
+
+        let alphabet: Vec<char> = TRAINING.chars().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let input = random_text(&mut rng, &alphabet);
+            let tokens = tokeniser.encode(&input).unwrap();
+            assert_eq!(tokeniser.reconstruct(&tokens).unwrap(), input);
+
+            // every piece boundary is also a token boundary
+            let mut boundaries = HashSet::from([0]);
+            let mut end = 0;
+            for &token in &tokens {
+                end += tokeniser.reconstruct_bytes(&[token]).unwrap().len();
+                boundaries.insert(end);
+            }
+            for (start, _) in config.pre_tokeniser.split(&input) {
+                assert!(boundaries.contains(&start), "a token spans {} in {:?}", start, input);
+            }
+        }
+
+        // "on the" is in the training text four times, but it's two pieces
+        assert!(tokeniser.token_id(&byte_level::encode(b" the")).is_some());
+        assert!(tokeniser.tokens().iter().all(|token| !byte_level::decode(token).starts_with(b"on the")));
+    }
+
+    #[test]
+    fn offsets_point_back_into_the_input() {
+        let folded = Normaliser { form: Some(UnicodeForm::Nfkc), strip_accents: true, collapse_whitespace: true, ..Normaliser::text8() };
+        let tokeniser = char_level_tokeniser().with_normaliser(folded).with_special_tokens(&[END_OF_TEXT]).with_allow_special(true);
+        let input = "THE Ca\u{0301}t\n\n  sat<|endoftext|>ﬁ";
+        let offsets = tokeniser.encode_with_offsets(input).unwrap();
+        let spans: Vec<(String, &str, Range<usize>)> = offsets.iter()
+            .map(|token| (tokeniser.reconstruct(&[token.id]).unwrap(), &input[token.bytes.clone()], token.chars.clone()))
+            .collect();
+        let expected = [("t", "T", 0..1), ("he ", "HE ", 1..4), ("c", "C", 4..5), ("at s", "a\u{0301}t\n\n  s", 5..13), ("at", "at", 13..15), ("<|endoftext|>", "<|endoftext|>", 15..28)]; // no f in the vocab
+        assert_eq!(spans, expected.map(|(token, text, chars)| (token.to_string(), text, chars)));
+
+        let alphabet: Vec<char> = TRAINING.chars().chain("<|endoftext|>日本語 🦀 É\u{0301}ﬁ\r\n".chars()).collect();
+        let tokenisers = [
+            byte_level_tokeniser().with_lossless().with_pre_tokeniser(PreTokeniser::gpt2()),
+            byte_level_tokeniser().with_normaliser(folded).with_special_tokens(&[END_OF_TEXT]).with_allow_special(true),
+            char_level_tokeniser().with_normaliser(Normaliser::text8()).with_unknown_policy(UnknownPolicy::Bytes),
+        ];
+        let mut rng = StdRng::seed_from_u64(8);
+        for tokeniser in tokenisers {
+            for _ in 0..CASES {
+                let input = random_text(&mut rng, &alphabet);
+                let offsets = tokeniser.encode_with_offsets(&input).unwrap();
+                assert_eq!(offsets.iter().map(|token| token.id).collect::<Vec<_>>(), tokeniser.encode(&input).unwrap());
+
+                let mut last = 0..0;
+                for token in &offsets {
+                    assert!(token.bytes.start < token.bytes.end && token.bytes.start >= last.start && token.bytes.end >= last.end);
+                    assert_eq!(input[..token.bytes.start].chars().count(), token.chars.start); // panics if it's not on a character boundary
+                    assert_eq!(input[token.bytes.clone()].chars().count(), token.chars.len());
+                    last = token.bytes.clone();
+
+                    // nothing is normalised away, so a token that's whole characters is exactly the text it came from
+                    let bytes = tokeniser.reconstruct_bytes(&[token.id]).unwrap();
+                    if tokeniser.is_lossless() && std::str::from_utf8(&bytes).is_ok() {
+                        assert_eq!(&input.as_bytes()[token.bytes.clone()], bytes);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batches_match_encoding_one_at_a_time() {
+        let tokeniser = byte_level_tokeniser().with_lossless().with_pre_tokeniser(PreTokeniser::gpt2());
+        let alphabet: Vec<char> = TRAINING.chars().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        let texts: Vec<String> = (0..CASES).map(|_| random_text(&mut rng, &alphabet)).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+
+        let batch = tokeniser.encode_batch(&texts).unwrap();
+        for (text, ids) in texts.iter().zip(&batch) {
+            assert_eq!(ids.iter().map(|&id| id as usize).collect::<Vec<_>>(), tokeniser.encode(text).unwrap());
+        }
+        assert_eq!(tokeniser.decode_batch(&batch).unwrap(), texts);
+
+        let inputs = tokeniser.encode_batch_for_model(&texts, Some(Truncation::new(8)), Some(Padding::longest(0))).unwrap();
+        for (input, ids) in inputs.iter().zip(&batch) {
+            let real = input.attention_mask.iter().filter(|&&mask| mask == 1).count();
+            assert_eq!(input.ids.len(), 8);
+            assert_eq!(input.ids[..real], ids[..ids.len().min(8)]);
+        }
+
+        let error = char_level_tokeniser().with_unknown_policy(UnknownPolicy::Error);
+        assert!(error.encode_batch(&["the cat", "the 🦀"]).is_err());
+    }
+
+    #[test]
+    fn decoding_an_id_outside_the_vocab_is_an_error() {
+        for tokeniser in [char_level_tokeniser(), byte_level_tokeniser()] {
+            let size = tokeniser.vocab_size();
+            let valid = tokeniser.encode("the cat").unwrap();
+            assert!(matches!(tokeniser.reconstruct(&[size]), Err(Error::UnknownId { id, vocab_size }) if id == size && vocab_size == size));
+            assert!(matches!(tokeniser.reconstruct_bytes(&[valid[0], size]), Err(Error::UnknownId { .. })));
+            let batch = vec![valid.iter().map(|&id| id as u32).collect(), vec![size as u32]];
+            assert!(matches!(tokeniser.decode_batch(&batch), Err(Error::UnknownId { .. })));
+            assert_eq!(tokeniser.reconstruct(&valid).unwrap(), "the cat");
+        }
+    }
+
+    #[test]
+    fn one_tokeniser_encodes_on_many_threads() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<Tokeniser>();
+
+        let tokeniser = std::sync::Arc::new(byte_level_tokeniser().with_pre_tokeniser(PreTokeniser::gpt2()));
+        let texts = ["the cat sat", "on the 🦀 mat", "The End!\n"];
+        let encodings: Vec<Encoding> = std::thread::scope(|scope| {
+            let handles: Vec<_> = texts.iter().map(|text| {
+                let tokeniser = tokeniser.clone();
+                scope.spawn(move || tokeniser.encoding(text).unwrap())
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        for (text, encoding) in texts.iter().zip(encodings) {
+            assert_eq!(encoding.ids, tokeniser.encode(text).unwrap());
+            assert_eq!(encoding.tokens.concat(), byte_level::encode(text.as_bytes()));
+            let offsets = tokeniser.encode_with_offsets(text).unwrap();
+            assert_eq!(encoding.offsets, offsets.iter().map(|token| token.bytes.clone()).collect::<Vec<_>>());
+            assert_eq!(encoding.char_offsets, offsets.iter().map(|token| token.chars.clone()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn config_is_saved_with_the_model() {
+        let config = ModelConfig { normaliser: Normaliser::text8(), pre_tokeniser: PreTokeniser::cl100k().with_split_digits(true), byte_level: false };
+        let mut trainer = Trainer::from_text(TRAINING, &config);
+        trainer.train(60).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+
+        let dir = std::env::temp_dir().join(format!("rs-tokeniser-config-{}", std::process::id()));
+        let path = dir.join("vocabulary.json");
+        crate::vocab::save_vocabulary(&vocab, &path).unwrap();
+        crate::vocab::save_merges(&merges, merges_path_for(&path)).unwrap();
+        crate::vocab::save_config(&config, config_path_for(&path)).unwrap();
+        let loaded = Tokeniser::from_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.config(), config);
+        let trained = Tokeniser::from_vocab(&vocab, merges).with_config(config);
+        assert_eq!(loaded.encode("The cat sat, 123 times.").unwrap(), trained.encode("The cat sat, 123 times.").unwrap());
+
+        // a bad pattern in the file is an error rather than quietly not splitting
+        let invalid = r#"{ "pre_tokeniser": { "pattern": { "Custom": "(" } } }"#;
+        assert!(serde_json::from_str::<ModelConfig>(invalid).is_err());
+        assert_eq!(serde_json::from_str::<ModelConfig>("{}").unwrap(), ModelConfig::default());
+    }
+
+    #[test]
+    fn loads_bundled_vocabularies() {
+        let names = bundled_vocabularies();
+        assert!(names.contains(&DEFAULT_BUNDLED.to_string()));
+        assert_eq!(vocabularies_in("output").unwrap(), names); // the same files as are built in
+
+        for name in names {
+            let tokeniser = Tokeniser::bundled(&name).unwrap();
+            assert_eq!(tokeniser.reconstruct(&tokeniser.get_tokens_from_text("The Quick Fox").unwrap()).unwrap(), "the quick fox");
+        }
+        assert!(matches!(Tokeniser::bundled("missing"), Err(Error::MissingVocab(_))));
+        assert_eq!(Tokeniser::from_dir("output", DEFAULT_BUNDLED).unwrap().tokens(), Tokeniser::bundled(DEFAULT_BUNDLED).unwrap().tokens());
+        assert!(matches!(Tokeniser::from_dir("output", "missing"), Err(Error::MissingVocab(_))));
+        assert!(matches!(Tokeniser::from_json("[1, 2]"), Err(Error::MalformedVocab { .. })));
+
+        let from_json = Tokeniser::from_json(r#"{"a": 1, "b": 2, "ab": 3}"#).unwrap();
+        assert_eq!(from_json.get_tokens_from_text("abba").unwrap().len(), 3);
+        let from_reader = Tokeniser::from_reader(r#"{"a": 1}"#.as_bytes()).unwrap();
+        assert_eq!(from_reader.vocab.len(), 1);
+    }
+
+    #[test]
+    fn ids_are_stable_across_loads() {
+        let words = HashMap::from([("the ".to_string(), 5), ("cat ".to_string(), 3), ("sat ".to_string(), 2), ("mat ".to_string(), 2)]);
+        let mut trainer = Trainer::from_word_counts(&words);
+        trainer.train(20).unwrap();
+        let (vocab, merges, _) = trainer.into_parts();
+
+        let dir = std::env::temp_dir().join(format!("rs-tokeniser-ids-{}", std::process::id()));
+        let path = dir.join("vocabulary.json");
+        crate::vocab::save_vocabulary(&vocab, &path).unwrap();
+        crate::vocab::save_merges(&merges, merges_path_for(&path)).unwrap();
+
+        let first = Tokeniser::from_file(&path).unwrap();
+        let second = Tokeniser::from_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first.vocab_size(), vocab.len());
+        assert_eq!(first.tokens(), second.tokens());
+        for (id, token) in first.tokens().iter().enumerate() {
+            assert_eq!(first.token_id(token), Some(id));
+            assert_eq!(second.token_id(token), Some(id));
+        }
+        assert_eq!(first.vocab_hash(), second.vocab_hash());
+        assert_eq!(first.encode("the cat sat").unwrap(), second.encode("the cat sat").unwrap());
+
+        // the ids in the file are used as they are, not worked out again
+        let swapped = Tokeniser::from_json(r#"[{"token": "a", "id": 1, "count": 1}, {"token": "ab", "id": 0, "count": 1}]"#).unwrap();
+        assert_eq!(swapped.tokens(), ["ab", "a"]);
+        let reordered = Tokeniser::from_json(r#"[{"token": "a", "id": 0, "count": 1}, {"token": "ab", "id": 1, "count": 1}]"#).unwrap();
+        assert_eq!(reordered.tokens(), ["a", "ab"]);
+        assert!(matches!(Tokeniser::from_json(r#"[{"token": "a", "id": 1, "count": 1}]"#), Err(Error::MalformedVocab { .. })));
+
+        // older token -> count files still load the same way every time
+        let legacy = r#"{"ab": 1, "ba": 1, "a": 1, "b": 1, "abc": 1}"#;
+        assert_eq!(Tokeniser::from_json(legacy).unwrap().tokens(), ["abc", "ab", "ba", "a", "b"]);
+        assert_eq!(Tokeniser::bundled(DEFAULT_BUNDLED).unwrap().tokens(), Tokeniser::bundled(DEFAULT_BUNDLED).unwrap().tokens());
+    }
+
+    #[test]
+    fn encode_replays_training_merges() {
+        let corpus = "the cat sat on the mat and the rat sat on the hat then the cat and the rat ate the mat";
+        let characters: Vec<String> = corpus.chars().map(|c| c.to_string()).collect();
+        let initial_vocab = crate::vocab::initialize_vocab(&characters);
+        let mut trainer = Trainer::new(characters, initial_vocab);
+        trainer.train(40).unwrap();
+        let (vocab, merges, trained) = trainer.into_parts();
+
+        let tokeniser = Tokeniser::from_vocab(&vocab, merges);
+        let encoded: Vec<String> = tokeniser.encode(corpus)
+            .unwrap()
+            .iter()
+            .map(|&id| tokeniser.reconstruct(&[id]).unwrap())
+            .collect();
+
+        assert_eq!(encoded, trained);
+    }
+
+    #[test]
+    fn merge_replay_matches_rewriting_the_whole_sequence() {
+        // what apply_merges() used to do: find the lowest ranked pair, rewrite every occurrence, start again
+        fn rewrite(tokeniser: &Tokeniser, input: &str) -> Vec<String> {
+            let mut pieces: Vec<String> = input.chars().map(String::from).collect();
+            while let Some(merge) = pieces.windows(2)
+                .filter_map(|pair| tokeniser.merges.iter().find(|merge| merge.left == pair[0] && merge.right == pair[1]))
+                .min_by_key(|merge| merge.rank)
+            {
+                let mut merged = Vec::new();
+                let mut i = 0;
+                while i < pieces.len() {
+                    if i + 1 < pieces.len() && pieces[i] == merge.left && pieces[i + 1] == merge.right {
+                        merged.push(merge.merged.clone());
+                        i += 2;
+                    } else {
+                        merged.push(pieces[i].clone());
+                        i += 1;
+                    }
+                }
+                pieces = merged;
+            }
+            pieces
+        }
+
+        let tokeniser = char_level_tokeniser();
+        let alphabet: Vec<char> = TRAINING.chars().collect();
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let input: String = (0..rng.gen_range(0..60)).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect();
+            let replayed: Vec<String> = tokeniser.apply_merges(&input).into_iter().map(|(_, start, end)| input[start..end].to_string()).collect();
+            assert_eq!(replayed, rewrite(&tokeniser, &input));
+        }
+    }
 }
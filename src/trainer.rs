@@ -0,0 +1,474 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::error::{Error, Result};
+use crate::byte_level;
+use crate::tokeniser::{Merge, ModelConfig};
+
+const NONE: u32 = u32::MAX; // end of the list / removed node
+
+type Pair = (u32, u32); // (left symbol, right symbol)
+
+// A pair waiting in the queue. Counts go stale as merges happen, so they get checked against the real count when popped
+struct Candidate {
+    count: i32,
+    left: Rc<str>,
+    right: Rc<str>,
+    pair: Pair,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // highest count first, ties go to the smaller pair so the merge order is reproducible
+        self.count.cmp(&other.count)
+            .then_with(|| (&other.left, &other.right).cmp(&(&self.left, &self.right)))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+// The corpus as a linked list of interned symbols, plus the count and positions of every adjacent pair.
+// Merging a pair only touches the places it occurs instead of recounting everything
+struct LinkedCorpus {
+    symbols: Vec<Rc<str>>, // symbol id -> token
+    symbol_ids: HashMap<Rc<str>, u32>,
+    tokens: Vec<u32>, // symbol at each node, NONE once the node has been merged into its left neighbour
+    next: Vec<u32>,
+    prev: Vec<u32>,
+    weights: Vec<i32>, // how many times the word each node belongs to occurs - empty when every node counts once
+    pair_counts: HashMap<Pair, i32>,
+    positions: HashMap<Pair, Vec<u32>>, // left node of every place the pair was seen, may contain stale entries
+    queue: BinaryHeap<Candidate>,
+}
+
+impl LinkedCorpus {
+    fn new(corpus: Vec<String>) -> Self {
+        let mut linked = LinkedCorpus {
+            symbols: Vec::new(),
+            symbol_ids: HashMap::new(),
+            tokens: Vec::with_capacity(corpus.len()),
+            next: Vec::with_capacity(corpus.len()),
+            prev: Vec::with_capacity(corpus.len()),
+            weights: Vec::new(),
+            pair_counts: HashMap::new(),
+            positions: HashMap::new(),
+            queue: BinaryHeap::new(),
+        };
+
+        let length = corpus.len() as u32;
+        for (node, token) in corpus.into_iter().enumerate() {
+            let node = node as u32;
+            let symbol = linked.intern(&token);
+            linked.tokens.push(symbol);
+            linked.prev.push(if node == 0 { NONE } else { node - 1 });
+            linked.next.push(if node + 1 == length { NONE } else { node + 1 });
+        }
+
+        linked.count_pairs();
+        linked
+    }
+
+    // Every unique word becomes its own short list of characters, weighted by how often the word occurs.
+    // Pairs never cross from one word into the next
+    fn from_words(word_counts: &HashMap<String, i32>) -> Self {
+        let mut words: Vec<(&String, &i32)> = word_counts.iter().collect();
+        words.sort(); // keep node order independent of HashMap order
+
+        let mut linked = LinkedCorpus {
+            symbols: Vec::new(),
+            symbol_ids: HashMap::new(),
+            tokens: Vec::new(),
+            next: Vec::new(),
+            prev: Vec::new(),
+            weights: Vec::new(),
+            pair_counts: HashMap::new(),
+            positions: HashMap::new(),
+            queue: BinaryHeap::new(),
+        };
+
+        for (word, &frequency) in words {
+            let start = linked.tokens.len() as u32;
+            let length = word.chars().count() as u32;
+            for (offset, c) in word.chars().enumerate() {
+                let node = start + offset as u32;
+                let symbol = linked.intern(&c.to_string());
+                linked.tokens.push(symbol);
+                linked.prev.push(if node == start { NONE } else { node - 1 });
+                linked.next.push(if node + 1 == start + length { NONE } else { node + 1 });
+                linked.weights.push(frequency);
+            }
+        }
+
+        linked.count_pairs();
+        linked
+    }
+
+    fn count_pairs(&mut self) {
+        for node in 0..self.tokens.len() as u32 {
+            let right = self.next[node as usize];
+            if right != NONE {
+                let pair = (self.tokens[node as usize], self.tokens[right as usize]);
+                *self.pair_counts.entry(pair).or_insert(0) += self.weight(node);
+                self.positions.entry(pair).or_default().push(node);
+            }
+        }
+
+        let pairs: Vec<Pair> = self.pair_counts.keys().copied().collect();
+        for pair in pairs {
+            self.enqueue(pair);
+        }
+    }
+
+    fn weight(&self, node: u32) -> i32 {
+        if self.weights.is_empty() { 1 } else { self.weights[node as usize] }
+    }
+
+    fn intern(&mut self, token: &str) -> u32 {
+        if let Some(&id) = self.symbol_ids.get(token) {
+            return id;
+        }
+        let id = self.symbols.len() as u32;
+        let symbol: Rc<str> = Rc::from(token);
+        self.symbols.push(symbol.clone());
+        self.symbol_ids.insert(symbol, id);
+        id
+    }
+
+    fn symbol(&self, id: u32) -> &str {
+        &self.symbols[id as usize]
+    }
+
+    fn enqueue(&mut self, pair: Pair) {
+        let count = self.pair_counts.get(&pair).copied().unwrap_or(0);
+        if count > 0 {
+            self.queue.push(Candidate {
+                count,
+                left: self.symbols[pair.0 as usize].clone(),
+                right: self.symbols[pair.1 as usize].clone(),
+                pair,
+            });
+        }
+    }
+
+    // Pops until the top of the queue matches a live count
+    fn best_pair(&mut self) -> Option<(Pair, i32)> {
+        while let Some(candidate) = self.queue.pop() {
+            if self.pair_counts.get(&candidate.pair) == Some(&candidate.count) {
+                return Some((candidate.pair, candidate.count));
+            }
+        }
+        None
+    }
+
+    fn add_count(&mut self, pair: Pair, delta: i32, changed: &mut HashSet<Pair>) {
+        let count = self.pair_counts.entry(pair).or_insert(0);
+        *count += delta;
+        if *count <= 0 {
+            self.pair_counts.remove(&pair);
+        }
+        changed.insert(pair);
+    }
+
+    // Merges every occurrence of the pair left to right, the same as rewriting the whole corpus would
+    fn merge(&mut self, pair: Pair, merged: u32) {
+        let mut positions = self.positions.remove(&pair).unwrap_or_default();
+        positions.sort_unstable(); // nodes are in corpus order, so this is left to right
+        let mut changed = HashSet::new();
+
+        for node in positions {
+            let right = self.next[node as usize];
+            if self.tokens[node as usize] != pair.0 || right == NONE || self.tokens[right as usize] != pair.1 {
+                continue; // already used up by an overlapping merge (eg "a a a")
+            }
+            let before = self.prev[node as usize];
+            let after = self.next[right as usize];
+            let weight = self.weight(node);
+
+            self.add_count(pair, -weight, &mut changed);
+            if before != NONE {
+                self.add_count((self.tokens[before as usize], pair.0), -weight, &mut changed);
+            }
+            if after != NONE {
+                self.add_count((pair.1, self.tokens[after as usize]), -weight, &mut changed);
+            }
+
+            // fold the right node into this one
+            self.tokens[node as usize] = merged;
+            self.tokens[right as usize] = NONE;
+            self.next[node as usize] = after;
+            if after != NONE {
+                self.prev[after as usize] = node;
+            }
+
+            if before != NONE {
+                let new_pair = (self.tokens[before as usize], merged);
+                self.add_count(new_pair, weight, &mut changed);
+                self.positions.entry(new_pair).or_default().push(before);
+            }
+            if after != NONE {
+                let new_pair = (merged, self.tokens[after as usize]);
+                self.add_count(new_pair, weight, &mut changed);
+                self.positions.entry(new_pair).or_default().push(node);
+            }
+        }
+
+        for pair in changed {
+            self.enqueue(pair);
+        }
+    }
+
+    fn into_tokens(self) -> Vec<String> {
+        self.tokens.iter()
+            .filter(|&&symbol| symbol != NONE)
+            .map(|&symbol| self.symbol(symbol).to_string())
+            .collect()
+    }
+}
+
+// Owns everything training needs - the corpus, the vocab being built and the merges made so far
+pub struct Trainer {
+    corpus: LinkedCorpus,
+    vocab: HashMap<String, i32>,
+    merges: Vec<Merge>,
+}
+
+impl Trainer {
+    // Trains over the corpus as one stream of characters, so tokens can span words (eg "ds the ")
+    pub fn new(corpus: Vec<String>, initial_vocab: HashMap<String, i32>) -> Self {
+        Trainer {
+            corpus: LinkedCorpus::new(corpus),
+            vocab: initial_vocab,
+            merges: Vec::new(),
+        }
+    }
+
+    // Trains on a table of unique words and how often they occur instead of the full character stream.
+    // Only pairs inside a word are counted, so tokens never span two words, for a fraction of the memory
+    pub fn from_word_counts(word_counts: &HashMap<String, i32>) -> Self {
+        let mut initial_vocab: HashMap<String, i32> = HashMap::new();
+        for (word, &frequency) in word_counts {
+            for c in word.chars() {
+                *initial_vocab.entry(c.to_string()).or_insert(0) += frequency;
+            }
+        }
+
+        Trainer {
+            corpus: LinkedCorpus::from_words(word_counts),
+            vocab: initial_vocab,
+            merges: Vec::new(),
+        }
+    }
+
+    // Trains on the pieces the config splits the text into (see ModelConfig::pieces), counted the same way as
+    // from_word_counts() so no token spans two pieces. A byte level config gets the whole byte alphabet
+    pub fn from_text(text: &str, config: &ModelConfig) -> Self {
+        let mut piece_counts: HashMap<String, i32> = HashMap::new();
+        for piece in config.pieces(text) {
+            *piece_counts.entry(piece).or_insert(0) += 1;
+        }
+
+        let trainer = Self::from_word_counts(&piece_counts);
+        if config.byte_level { trainer.with_alphabet(&byte_level::alphabet()) } else { trainer }
+    }
+
+    // Makes sure every symbol in the alphabet ends up in the vocab even if the corpus never used it,
+    // eg byte_level::alphabet() so a byte level vocab can encode any input
+    pub fn with_alphabet(mut self, alphabet: &[String]) -> Self {
+        for symbol in alphabet {
+            self.vocab.entry(symbol.clone()).or_insert(0);
+        }
+        self
+    }
+
+    // Merges the current most frequent pair. None once there's nothing left to merge
+    pub fn step(&mut self) -> Option<&Merge> {
+        let (pair, frequency) = self.corpus.best_pair()?;
+
+        let left = self.corpus.symbol(pair.0).to_string();
+        let right = self.corpus.symbol(pair.1).to_string();
+        let new_token = format!("{}{}", left, right);
+
+        let new_count = self.vocab.get(&left).copied().unwrap_or(0).saturating_add(self.vocab.get(&right).copied().unwrap_or(0));
+        self.vocab.insert(new_token.clone(), new_count);
+
+        let merged = self.corpus.intern(&new_token);
+        self.corpus.merge(pair, merged);
+
+        self.merges.push(Merge {
+            left,
+            right,
+            merged: new_token,
+            rank: self.merges.len(),
+            count: frequency,
+        });
+        self.merges.last()
+    }
+
+    // Keeps merging until the vocab reaches vocab_size or there are no pairs left.
+    // Fails if the vocab needs to grow but the corpus never had a pair to merge (eg it's empty)
+    pub fn train(&mut self, vocab_size: usize) -> Result<()> {
+        self.train_with_progress(vocab_size, |_| {})
+    }
+
+    // train(), handing the trainer to progress after every merge so the caller can report how far it's got
+    pub fn train_with_progress(&mut self, vocab_size: usize, mut progress: impl FnMut(&Self)) -> Result<()> {
+        while self.vocab.len() < vocab_size {
+            if self.step().is_none() {
+                if self.merges.is_empty() {
+                    return Err(Error::Training(format!("the corpus has no pairs to merge, the vocab is stuck at {} tokens", self.vocab.len())));
+                }
+                break;
+            }
+            progress(self);
+        }
+        Ok(())
+    }
+
+    pub fn vocab(&self) -> &HashMap<String, i32> {
+        &self.vocab
+    }
+
+    pub fn merges(&self) -> &[Merge] {
+        &self.merges
+    }
+
+    // The vocab, the merges in rank order and the corpus split into its current tokens
+    pub fn into_parts(self) -> (HashMap<String, i32>, Vec<Merge>, Vec<String>) {
+        (self.vocab, self.merges, self.corpus.into_tokens())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocab::initialize_vocab;
+
+    const CORPUS: &str = "aaaa abab the cat sat on the mat and the rat sat on the hat then the cat and the rat ate the mat aaa";
+
+    // The original training loop - recount every pair, merge the best one, repeat
+    fn reference_bpe(mut corpus: Vec<String>, vocab_size: usize, mut vocab: HashMap<String, i32>) -> (HashMap<String, i32>, Vec<Merge>, Vec<String>) {
+        let mut merges = Vec::new();
+
+        while vocab.len() < vocab_size {
+            let mut pair_count: HashMap<(String, String), i32> = HashMap::new();
+            for window in corpus.windows(2) {
+                *pair_count.entry((window[0].clone(), window[1].clone())).or_insert(0) += 1;
+            }
+
+            let Some((best_pair, frequency)) = pair_count.into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0))) else {
+                break;
+            };
+
+            let new_token = format!("{}{}", best_pair.0, best_pair.1);
+            let new_count = vocab[&best_pair.0] + vocab[&best_pair.1];
+            vocab.insert(new_token.clone(), new_count);
+
+            let mut i = 0;
+            while i + 1 < corpus.len() {
+                if corpus[i] == best_pair.0 && corpus[i + 1] == best_pair.1 {
+                    corpus[i] = new_token.clone();
+                    corpus.remove(i + 1);
+                } else {
+                    i += 1;
+                }
+            }
+
+            merges.push(Merge {
+                rank: merges.len(),
+                left: best_pair.0,
+                right: best_pair.1,
+                merged: new_token,
+                count: frequency,
+            });
+        }
+
+        (vocab, merges, corpus)
+    }
+
+    #[test]
+    fn trainer_matches_reference() {
+        let corpus: Vec<String> = CORPUS.chars().map(|c| c.to_string()).collect();
+        let initial_vocab = initialize_vocab(&corpus);
+
+        let expected = reference_bpe(corpus.clone(), 45, initial_vocab.clone());
+        let mut trainer = Trainer::new(corpus, initial_vocab);
+        trainer.train(45).unwrap();
+        let actual = trainer.into_parts();
+
+        assert_eq!(actual.1, expected.1);
+        assert_eq!(actual.2, expected.2);
+        assert_eq!(actual.0, expected.0);
+    }
+
+    #[test]
+    fn word_counts_weight_pairs() {
+        let word_counts: HashMap<String, i32> = [("the ", 4), ("cat ", 2), ("hat ", 1), ("that ", 3)]
+            .into_iter()
+            .map(|(word, count)| (word.to_string(), count))
+            .collect();
+
+        let mut trainer = Trainer::from_word_counts(&word_counts);
+        let mut reported = Vec::new();
+        trainer.train_with_progress(20, |trainer| reported.push(trainer.merges().len())).unwrap();
+
+        let merges = trainer.merges();
+        assert_eq!(reported, (1..=merges.len()).collect::<Vec<_>>()); // once after every merge
+        assert_eq!(merges[0].merged, "th"); // 4 times in "the " and 3 in "that "
+        assert_eq!(merges[0].count, 7);
+        assert!(trainer.vocab().contains_key("the "));
+        assert!(merges.iter().all(|merge| !merge.merged.trim_end().contains(' '))); // nothing spans a word boundary
+    }
+
+    #[test]
+    fn reading_pieces_in_blocks_counts_the_same() {
+        // long enough to be read in several blocks, with pieces cut across them
+        let text: String = CORPUS.split(' ').cycle().take(40_000).enumerate()
+            .map(|(i, word)| match i % 7 {
+                0 => format!("{}{},\n", word, i),
+                3 => format!("Ca\u{0301}fe\u{0301} {}  \t", word.to_uppercase()),
+                _ => format!("{} ", word),
+            })
+            .collect();
+        let normaliser = crate::Normaliser { form: Some(crate::normaliser::UnicodeForm::Nfc), strip_accents: true, collapse_whitespace: true, ..crate::Normaliser::text8() };
+        let config = ModelConfig { normaliser, pre_tokeniser: crate::PreTokeniser::gpt2().with_split_digits(true), ..ModelConfig::default() };
+
+        let path = std::env::temp_dir().join(format!("rs-tokeniser-pieces-{}.txt", std::process::id()));
+        std::fs::write(&path, &text).unwrap();
+        let piece_counts = crate::vocab::read_piece_counts(&path, usize::MAX, &config).unwrap();
+        let limited = crate::vocab::read_piece_counts(&path, 10, &config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut all_at_once: HashMap<String, i32> = HashMap::new();
+        for piece in config.pieces(&text) {
+            *all_at_once.entry(piece).or_insert(0) += 1;
+        }
+        assert_eq!(piece_counts, all_at_once);
+        assert_eq!(limited.values().sum::<i32>(), 10);
+
+        let mut from_text = Trainer::from_text(&text, &config);
+        let mut from_counts = Trainer::from_word_counts(&piece_counts);
+        from_text.train(60).unwrap();
+        from_counts.train(60).unwrap();
+        assert_eq!(from_text.merges(), from_counts.merges());
+    }
+
+    #[test]
+    fn empty_corpus_fails() {
+        let mut trainer = Trainer::new(Vec::new(), HashMap::new());
+        assert!(matches!(trainer.train(10), Err(Error::Training(_))));
+    }
+}
@@ -1,7 +1,7 @@
 use eframe::{egui, App, Frame};
 use egui::{CentralPanel, Context, RichText};
 
-use crate::tokeniser::Tokeniser;
+use rs_tokeniser::{Encoding, Tokeniser};
 
 
 
@@ -10,7 +10,8 @@ pub struct TokenVisualiser {
     pub text: String,
     pub tokeniser: Tokeniser,
     pub last_text: String, // To store the last state of the text
-    pub tokenised_text: Vec<String>, // To store the tokenised text
+    pub encoding: Encoding, // the tokenised text, and where each token came from for showing the selected ones
+    pub error: Option<String>, // why the text couldn't be tokenised, eg a character the vocab doesn't have
 }
 
 impl TokenVisualiser {
@@ -19,7 +20,8 @@ impl TokenVisualiser {
             text,
             tokeniser,
             last_text,
-            tokenised_text,
+            encoding,
+            error,
         } = self;
 
         let output = egui::TextEdit::multiline(text)
@@ -35,6 +37,19 @@ impl TokenVisualiser {
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.spacing_mut().item_spacing.x = 0.0;
+            ui.label("Selected tokens: ");
+            if let Some(text_cursor_range) = output.cursor_range {
+                let selected_chars = text_cursor_range.as_sorted_char_range();
+                let selected: Vec<String> = encoding.char_offsets.iter().zip(&encoding.ids) // every token that overlaps the selection
+                    .filter(|(chars, _)| chars.start < selected_chars.end && selected_chars.start < chars.end)
+                    .map(|(_, id)| id.to_string())
+                    .collect();
+                ui.code(selected.join(" "));
+            }
+        });
+
 
         if ui.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::Y)) {
             if let Some(text_cursor_range) = output.cursor_range {
@@ -55,19 +70,29 @@ impl TokenVisualiser {
         // Check if the text has changed
         if last_text != text {
             *last_text = text.clone(); // Update last_text
-            *tokenised_text = tokeniser.tokenise(&self.text); // Update tokenised text
-            //*tokenised_text = tokeniser.extract_tokens(self.text.as_str());
-            //println!("{}", tokenised_text.len());
+            match tokeniser.encoding(text) { // Update tokenised text
+                Ok(tokenised) => {
+                    *encoding = tokenised;
+                    *error = None;
+                },
+                Err(e) => {
+                    *encoding = Encoding::default();
+                    *error = Some(e.to_string());
+                },
+            }
         }
 
         // display the tokenised text with background highlight
         ui.label("Tokenised text:");
         ui.group(|ui| {
+            if let Some(error) = error {
+                ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+            }
             let font_size = 26.0;
         
             ui.horizontal_wrapped(|ui| {
-                for token in tokenised_text {
-                    let color = generate_color_for_token(&token); // colour has to be light enough to see text on
+                for (token, (r, g, b)) in encoding.tokens.iter().zip(encoding.colours()) { // the same colours the CLI prints in
+                    let color = egui::Color32::from_rgb(r, g, b);
                     let text = RichText::new(token.clone())
                         .size(font_size)
                         .background_color(color);
@@ -85,11 +110,11 @@ struct MyApp {
     text_edit_demo: TokenVisualiser,
 }
 
-impl Default for MyApp {
-    fn default() -> Self {
+impl MyApp {
+    fn new(tokeniser: Tokeniser) -> Self {
         Self {
             text_edit_demo: TokenVisualiser {
-                tokeniser: Tokeniser::new().unwrap(), // Initialize the Tokeniser instance here
+                tokeniser,
                 ..Default::default()
             },
         }
@@ -104,106 +129,9 @@ impl App for MyApp {
     }
 }
 
-pub fn run() {
+pub fn run(tokeniser: Tokeniser) -> eframe::Result<()> {
     let options = eframe::NativeOptions {
         ..Default::default()
     };
-    let _ = eframe::run_native("Simple Text Editor", options, Box::new(|_cc| Box::new(MyApp::default())));
+    eframe::run_native("Simple Text Editor", options, Box::new(|_cc| Box::new(MyApp::new(tokeniser))))
 }
-
-
-
-// Generate a deterministic color from a string.
-fn generate_color_for_token(token: &str) -> egui::Color32 {
-    let mut hash: u32 = 0;
-    for byte in token.bytes() {
-        hash = hash.wrapping_mul(37_u32).wrapping_add(u32::from(byte));
-    }
-    let r = ((hash & 0xFF0000) >> 16) as u8;
-    let g = ((hash & 0x00FF00) >> 8) as u8;
-    let b = (hash & 0x0000FF) as u8;
-
-    // Convert to HSL and ensure lightness is above a certain threshold
-    let (h, s, mut l) = rgb_to_hsl(r, g, b);
-    if l < 0.7 {
-        l = 0.7;
-    }
-    let (nr, ng, nb) = hsl_to_rgb(h, s, l);
-
-    egui::Color32::from_rgb(nr, ng, nb)
-}
-
-// the following was mostly stolen
-
-fn rgb_to_hsl(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
-    let r = r as f32 / 255.0;
-    let g = g as f32 / 255.0;
-    let b = b as f32 / 255.0;
-
-    let max = r.max(g).max(b);
-    let min = r.min(g).min(b);
-    let l = (max + min) / 2.0;
-
-    let mut h = 0.0;
-    let mut s = 0.0;
-
-    if max != min {
-        let d = max - min;
-        s = if l > 0.5 { d / (2.0 - max - min) } else { d / (max + min) };
-        h = match max {
-            _ if max == r => (g - b) / d + if g < b { 6.0 } else { 0.0 },
-            _ if max == g => (b - r) / d + 2.0,
-            _ => (r - g) / d + 4.0,
-        };
-        h /= 6.0;
-    }
-
-    (h, s, l)
-}
-
-fn hsl_to_rgb(h: f32, s: f32, l: f32) -> (u8, u8, u8) {
-    let r;
-    let g;
-    let b;
-
-    if s == 0.0 {
-        r = l;
-        g = l;
-        b = l;
-    } else {
-        let q = if l < 0.5 {
-            l * (1.0 + s)
-        } else {
-            l + s - l * s
-        };
-        let p = 2.0 * l - q;
-
-        r = hue_to_rgb(p, q, h + 1.0 / 3.0);
-        g = hue_to_rgb(p, q, h);
-        b = hue_to_rgb(p, q, h - 1.0 / 3.0);
-    }
-
-    (
-        (r * 255.0) as u8,
-        (g * 255.0) as u8,
-        (b * 255.0) as u8,
-    )
-}
-
-fn hue_to_rgb(p: f32, q: f32, mut t: f32) -> f32 {
-    if t < 0.0 {
-        t += 1.0;
-    } else if t > 1.0 {
-        t -= 1.0;
-    }
-
-    if t < 1.0 / 6.0 {
-        p + (q - p) * 6.0 * t
-    } else if t < 1.0 / 2.0 {
-        q
-    } else if t < 2.0 / 3.0 {
-        p + (q - p) * (2.0 / 3.0 - t) * 6.0
-    } else {
-        p
-    }
-}
\ No newline at end of file
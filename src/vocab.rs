@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde::{de, Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::normaliser::Normaliser;
+use crate::tokeniser::{push_utf8, Merge, ModelConfig};
+
+const READ_BLOCK: usize = 1 << 16; // bytes read_piece_counts() splits at a time
+
+// One line of a saved vocabulary. The id is written out so a token keeps it for good, whatever order the vocab is loaded in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VocabEntry {
+    pub token: String,
+    pub id: usize,
+    pub count: i32,
+}
+
+// What a vocabulary file can hold: entries with their ids, or the older token -> count map (the initial vocab, and
+// anything saved before ids were written out), which gets its ids from assign_ids()
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum VocabFile {
+    Entries(Vec<VocabEntry>),
+    Counts(HashMap<String, i32>),
+}
+
+impl VocabFile {
+    // Every token in id order
+    pub(crate) fn into_tokens(self, source: impl Display) -> Result<Vec<String>> {
+        let entries = match self {
+            VocabFile::Counts(counts) => return Ok(assign_ids(&counts)),
+            VocabFile::Entries(entries) => entries,
+        };
+
+        let mut tokens: Vec<Option<String>> = vec![None; entries.len()];
+        for entry in entries {
+            match tokens.get_mut(entry.id) {
+                Some(slot @ None) => *slot = Some(entry.token),
+                Some(Some(_)) => return Err(Error::malformed(source, de::Error::custom(format!("id {} is used twice", entry.id)))),
+                None => return Err(Error::malformed(source, de::Error::custom(format!("id {} is past the end of the vocab", entry.id)))),
+            }
+        }
+        Ok(tokens.into_iter().flatten().collect()) // every slot is filled, there are as many ids as slots and none repeat
+    }
+}
+
+// The ids a vocabulary gets when it's first saved: longest tokens first (which is how the vocab has always been ordered),
+// then by the token itself so tokens of the same length don't end up in HashMap order
+pub fn assign_ids(vocab: &HashMap<String, i32>) -> Vec<String> {
+    let mut tokens: Vec<String> = vocab.keys().cloned().collect();
+    tokens.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    tokens
+}
+
+// Saves the vocab as a list of VocabEntry, one per line, in id order
+pub fn save_vocabulary<P: AsRef<Path>>(vocab: &HashMap<String, i32>, file_path: P) -> Result<()> {
+    // Ensure the directory exists
+    let path = file_path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut lines = Vec::with_capacity(vocab.len());
+    for (id, token) in assign_ids(vocab).into_iter().enumerate() {
+        let entry = VocabEntry { count: vocab[&token], token, id };
+        lines.push(serde_json::to_string(&entry).map_err(|e| Error::malformed(path.display(), e))?);
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(format!("[\n  {}\n]\n", lines.join(",\n  ")).as_bytes())?;
+    Ok(())
+}
+
+pub fn save_merges<P: AsRef<Path>>(merges: &[Merge], file_path: P) -> Result<()> {
+    // Written next to the vocabulary (see tokeniser::merges_path_for), ordered by rank
+    let path = file_path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = File::create(path)?;
+    let json = serde_json::to_string_pretty(merges).map_err(|e| Error::malformed(path.display(), e))?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+// Written next to the vocabulary too (see tokeniser::config_path_for), so it's encoded the way it was trained
+pub fn save_config<P: AsRef<Path>>(config: &ModelConfig, file_path: P) -> Result<()> {
+    let path = file_path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = File::create(path)?;
+    let json = serde_json::to_string_pretty(config).map_err(|e| Error::malformed(path.display(), e))?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+pub fn initialize_vocab(data: &[String]) -> HashMap<String, i32> {
+    let mut vocab = HashMap::new();
+    for char in data {
+        *vocab.entry(char.to_string()).or_insert(0) += 1;
+    }
+    vocab
+}
+
+pub fn save_initial_vocab<P: AsRef<Path>>(vocab: &HashMap<String, i32>, file_path: P) -> Result<()> {
+    let path = file_path.as_ref();
+    let file = File::create(path);
+    match file {
+        Ok(mut f) => {
+            let json = serde_json::to_string_pretty(vocab).map_err(|e| Error::malformed(path.display(), e))?;
+            f.write_all(json.as_bytes())?;
+            Ok(())
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn load_initial_vocab<P: AsRef<Path>>(file_path: P) -> Result<HashMap<String, i32>> {
+    let path = file_path.as_ref();
+    if !path.exists() {
+        return Err(Error::MissingVocab(path.to_path_buf()));
+    }
+    let json = fs::read_to_string(path)?;
+    let vocab: HashMap<String, i32> = serde_json::from_str(&json).map_err(|e| Error::malformed(path.display(), e))?;
+    Ok(vocab)
+}
+
+pub fn read_words<P: AsRef<Path>>(file_path: P, word_count: usize) -> Result<Vec<String>> {
+    // Open the file
+    let file = File::open(file_path)?;
+    let reader = BufReader::new(file);
+    
+    // Initialize a vector to store the characters
+    let mut contents = Vec::new();
+    let mut total_words = 0;
+
+    // Iterate through lines
+    for line_result in reader.lines() {
+        let line = line_result?;
+
+        // Split the line into words using whitespace as the delimiter
+        for word in line.split_whitespace() {
+            total_words += 1;
+
+            // Convert each word to characters and add spaces between words
+            for char in Normaliser::text8().normalise(word).chars() {
+                contents.push(char.to_string());
+            }
+            contents.push(" ".to_string()); // Add a space after each word
+            
+            // Stop if we've reached the desired word count
+            if total_words >= word_count {
+                break;
+            }
+        }
+
+        // Stop the outer loop if we've reached the desired word count
+        if total_words >= word_count {
+            break;
+        }
+    }
+
+    // Remove the last space added if it exists
+    if let Some(last) = contents.last() { 
+        if last == " " {
+            contents.pop();
+        }
+    }
+
+    Ok(contents)
+}
+
+// Counts each unique word for Trainer::from_word_counts instead of keeping every character around like read_words() does.
+// Words keep the trailing space read_words() gives them, so the vocab ends up in the same format
+pub fn read_word_counts<P: AsRef<Path>>(file_path: P, word_count: usize) -> Result<HashMap<String, i32>> {
+    let file = File::open(file_path)?;
+    let reader = BufReader::new(file);
+
+    let mut word_counts: HashMap<String, i32> = HashMap::new();
+    let mut total_words = 0;
+
+    // split on spaces rather than lines - text8 is a single 100 MB line
+    for chunk in reader.split(b' ') {
+        let chunk = chunk?;
+        for word in String::from_utf8_lossy(&chunk).split_whitespace() {
+            *word_counts.entry(format!("{} ", Normaliser::text8().normalise(word))).or_insert(0) += 1;
+            total_words += 1;
+
+            if total_words >= word_count {
+                return Ok(word_counts);
+            }
+        }
+    }
+
+    Ok(word_counts)
+}
+
+// read_word_counts() for any ModelConfig: counts each unique piece the config splits the input into, reading a block
+// at a time. The last two pieces of a block wait for the next one in case more text changes how they split.
+// Stops after piece_count pieces. Without a pre-tokeniser the whole input is one piece, so use read_words() for that
+pub fn read_piece_counts<P: AsRef<Path>>(file_path: P, piece_count: usize, config: &ModelConfig) -> Result<HashMap<String, i32>> {
+    let mut reader = BufReader::with_capacity(READ_BLOCK, File::open(file_path)?);
+
+    let mut piece_counts: HashMap<String, i32> = HashMap::new();
+    let mut total_pieces = 0;
+    let mut partial = Vec::new(); // the start of a character cut off at the end of the last block
+    let mut decoded = String::new(); // not normalised yet
+    let mut text = String::new(); // normalised, not counted yet
+
+    loop {
+        let block = reader.fill_buf()?;
+        let done = block.is_empty();
+        let length = block.len();
+        partial.extend_from_slice(block);
+        reader.consume(length);
+
+        // normalising can join characters (eg composing accents), so the end of the block waits until it's somewhere that can't
+        push_utf8(&mut decoded, &mut partial, done);
+        let ready = if done { decoded.len() } else { config.normaliser.last_separable(&decoded).unwrap_or(0) };
+        text.push_str(&config.normaliser.normalise(&decoded[..ready]));
+        decoded.drain(..ready);
+
+        let pieces = config.pre_tokeniser.split(&text);
+        let finished = if done { pieces.len() } else { pieces.len().saturating_sub(2) };
+        for &(start, end) in &pieces[..finished] {
+            *piece_counts.entry(config.symbols(&text[start..end])).or_insert(0) += 1;
+            total_pieces += 1;
+
+            if total_pieces >= piece_count {
+                return Ok(piece_counts);
+            }
+        }
+
+        if done {
+            return Ok(piece_counts);
+        }
+        if let Some(&(_, end)) = pieces[..finished].last() {
+            text.drain(..end);
+        }
+    }
+}
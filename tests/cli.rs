@@ -0,0 +1,34 @@
+// Runs the built binary, the way it's used from a shell
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rs-tokeniser"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn lossless_round_trips_through_the_bundled_vocab() {
+    let input = "The quick, brown fox!\nIt's 25°C — naïve café 🦀\n";
+    let encoded = run(&["encode", "--lossless"], input.as_bytes());
+    assert!(encoded.status.success(), "{}", String::from_utf8_lossy(&encoded.stderr));
+
+    let decoded = run(&["decode", "--lossless"], &encoded.stdout);
+    assert!(decoded.status.success(), "{}", String::from_utf8_lossy(&decoded.stderr));
+    assert_eq!(String::from_utf8(decoded.stdout).unwrap(), input);
+}
+
+#[test]
+fn byte_level_needs_a_byte_level_vocab() {
+    // the bundled vocab is characters, so --byte-level would drop everything it doesn't have
+    let encoded = run(&["encode", "--byte-level"], b"The quick, brown fox!");
+    assert!(!encoded.status.success());
+    assert!(String::from_utf8_lossy(&encoded.stderr).contains("--byte-level"));
+}